@@ -68,7 +68,7 @@ impl SshTerminal for Asteroids {
                     velocity: (rand.random_range(-10..10), rand.random_range(-10..0)),
                     pos: start,
                     start,
-                    color: COLOGS.choose(&mut rand).unwrap().clone(),
+                    color: *COLOGS.choose(&mut rand).unwrap(),
                 });
 
                 self.spawn = rand.random_range(0..3);
@@ -94,7 +94,7 @@ impl SshTerminal for Asteroids {
                     x: x.into(),
                     y: y.into(),
                     radius: 1f64,
-                    color: ele.color.clone(),
+                    color: ele.color,
                 });
                 false
             }
@@ -136,7 +136,7 @@ impl SshTerminal for Asteroids {
                         current_y as f64,
                         start_x as f64,
                         start_y as f64,
-                        ele.color.clone(),
+                        ele.color,
                     ));
                 }
 
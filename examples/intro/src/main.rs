@@ -25,7 +25,7 @@ async fn main() {
         .unwrap();
 }
 
-const BOOT_SPLASH: &[&'static str] = &[
+const BOOT_SPLASH: &[&str] = &[
     "[ DONE ] Checking if meme driven development is real",
     "[ DONE ] Petting Java",
     "[ DONE ] Making the ThePrimeagen cry after I steal his terminal shop intro",
@@ -88,7 +88,7 @@ impl SshTerminal for IntroTerminal {
         //Overlay render
         let cursor_color = {
             let step = self.phase / 10;
-            if step % 2 == 0 {
+            if step.is_multiple_of(2) {
                 Color::LightMagenta
             } else {
                 Color::Reset
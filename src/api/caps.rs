@@ -0,0 +1,65 @@
+use russh::Pty;
+
+pub use termwiz::caps::ColorLevel;
+
+/// What the client terminal can do, worked out from its `TERM` and the environment it sent.
+/// Clients send most of their environment after the session started, the values can change
+/// during the first few frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub term: String,
+    pub color: ColorLevel,
+    /// The client locale uses UTF-8, without it stick to ASCII
+    pub unicode: bool,
+    /// OSC 8 hyperlinks are shown as links instead of garbage
+    pub hyperlinks: bool,
+    /// Frames wrapped in synchronized update mode 2026 are shown at once
+    pub synchronized_output: bool,
+    pub graphics: Graphics,
+}
+
+/// How [`crate::api::widgets::Image`] gets pixels on screen
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Graphics {
+    /// Colored `▀` characters, two pixels per cell. Works everywhere with 256 colors or more
+    #[default]
+    HalfBlocks,
+    Sixel,
+    /// The kitty graphics protocol
+    Kitty,
+}
+
+/// Terminal modes the client sent with its pty request, they decide what some keys mean
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalModes {
+    /// Byte the Backspace key sends, DEL for most clients and ^H for some Windows ones.
+    /// The other one is reported as Ctrl+H or Delete
+    pub erase: Option<u8>,
+    /// Enter sends CR, so a lone LF is reported as Ctrl+J
+    pub icrnl: bool,
+    /// LF is Enter even with [`TerminalModes::icrnl`]
+    pub inlcr: bool,
+    /// CR is dropped, for clients that send CR LF for Enter
+    pub igncr: bool,
+    /// Whether the client sends UTF-8, invalid UTF-8 is read as Latin-1 when it says it does not
+    pub utf8: Option<bool>,
+}
+
+impl TerminalModes {
+    pub(crate) fn from_pty(modes: &[(Pty, u32)]) -> Self {
+        let mut out = Self::default();
+        for (mode, value) in modes {
+            let on = *value != 0;
+            match mode {
+                // 0 and 255 are how the different platforms say the character is disabled
+                Pty::VERASE => out.erase = u8::try_from(*value).ok().filter(|x| !matches!(x, 0 | 255)),
+                Pty::ICRNL => out.icrnl = on,
+                Pty::INLCR => out.inlcr = on,
+                Pty::IGNCR => out.igncr = on,
+                Pty::IUTF8 => out.utf8 = Some(on),
+                _ => {}
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,448 @@
+use std::{fmt, str::FromStr};
+
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Clear, Paragraph, Widget},
+};
+use termwiz::input::{KeyCode, KeyEvent, Modifiers};
+
+use crate::api::term::{KeyEventKind, KeyboardEvent};
+
+/// A key together with the modifiers held while pressing it, parsed from strings like `ctrl+d`,
+/// `shift+tab` or `f5`. `shift+a` is the same as `A`, and `+` on its own or at the end is the plus key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyChord {
+    pub key: KeyCode,
+    pub modifiers: Modifiers,
+}
+
+impl KeyChord {
+    pub fn new(key: KeyCode, modifiers: Modifiers) -> Self {
+        Self { key, modifiers }
+    }
+
+    pub fn matches(&self, key: KeyCode, modifiers: Modifiers) -> bool {
+        self.key == key && self.modifiers == modifiers
+    }
+}
+
+impl From<KeyCode> for KeyChord {
+    fn from(key: KeyCode) -> Self {
+        Self::new(key, Modifiers::NONE)
+    }
+}
+
+impl From<char> for KeyChord {
+    fn from(c: char) -> Self {
+        Self::new(KeyCode::Char(c), Modifiers::NONE)
+    }
+}
+
+impl From<&KeyEvent> for KeyChord {
+    fn from(event: &KeyEvent) -> Self {
+        Self::new(event.key, event.modifiers)
+    }
+}
+
+impl FromStr for KeyChord {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || crate::Error::InvalidKeyBinding(s.to_string());
+
+        let (mods, key) = match s.strip_suffix('+') {
+            // A trailing `+` is the plus key itself
+            Some(rest) if rest.is_empty() || rest.ends_with('+') => (rest.strip_suffix('+'), "+"),
+            _ => match s.rsplit_once('+') {
+                Some((mods, key)) => (Some(mods), key),
+                None => (None, s),
+            },
+        };
+
+        let mut modifiers = Modifiers::NONE;
+        for modifier in mods.into_iter().flat_map(|x| x.split('+')) {
+            modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => Modifiers::CTRL,
+                "alt" | "meta" => Modifiers::ALT,
+                "shift" => Modifiers::SHIFT,
+                "super" => Modifiers::SUPER,
+                _ => return Err(invalid()),
+            };
+        }
+
+        let mut chars = key.chars();
+        let key = match (chars.next(), chars.next()) {
+            // Shifted letters are reported as the upper case letter without SHIFT
+            (Some(c), None) if modifiers.contains(Modifiers::SHIFT) && c.is_ascii_alphabetic() => {
+                modifiers -= Modifiers::SHIFT;
+                KeyCode::Char(c.to_ascii_uppercase())
+            }
+            // Terminals report control chords with the lower case letter
+            (Some(c), None) if modifiers.contains(Modifiers::CTRL) => {
+                KeyCode::Char(c.to_ascii_lowercase())
+            }
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match key.to_ascii_lowercase().as_str() {
+                "space" => KeyCode::Char(' '),
+                "enter" | "return" => KeyCode::Enter,
+                "esc" | "escape" => KeyCode::Escape,
+                "tab" => KeyCode::Tab,
+                "backspace" => KeyCode::Backspace,
+                "up" => KeyCode::UpArrow,
+                "down" => KeyCode::DownArrow,
+                "left" => KeyCode::LeftArrow,
+                "right" => KeyCode::RightArrow,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                "insert" => KeyCode::Insert,
+                "delete" | "del" => KeyCode::Delete,
+                name => {
+                    let number = name.strip_prefix('f').and_then(|x| x.parse::<u8>().ok());
+                    match number {
+                        Some(number @ 1..=35) => KeyCode::Function(number),
+                        _ => return Err(invalid()),
+                    }
+                }
+            },
+        };
+
+        Ok(Self { key, modifiers })
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (Modifiers::CTRL, "Ctrl+"),
+            (Modifiers::ALT, "Alt+"),
+            (Modifiers::SHIFT, "Shift+"),
+            (Modifiers::SUPER, "Super+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(name)?;
+            }
+        }
+
+        match self.key {
+            KeyCode::Char(' ') => f.write_str("Space"),
+            KeyCode::Char(c) => write!(f, "{c}"),
+            KeyCode::Escape => f.write_str("Esc"),
+            KeyCode::UpArrow => f.write_str("Up"),
+            KeyCode::DownArrow => f.write_str("Down"),
+            KeyCode::LeftArrow => f.write_str("Left"),
+            KeyCode::RightArrow => f.write_str("Right"),
+            KeyCode::Function(n) => write!(f, "F{n}"),
+            key => write!(f, "{key:?}"),
+        }
+    }
+}
+
+/// Outcome of feeding a key to a [`Keymap`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeymapMatch<A> {
+    Action(A),
+    /// The key started or continued a sequence, more keys are needed
+    Pending,
+    Unbound,
+}
+
+struct Binding<A> {
+    keys: Vec<KeyChord>,
+    action: A,
+    description: String,
+}
+
+/// Maps key chords and sequences like `g g` to app actions
+pub struct Keymap<A> {
+    bindings: Vec<Binding<A>>,
+    pending: Vec<KeyChord>,
+}
+
+impl<A> Default for Keymap<A> {
+    fn default() -> Self {
+        Self {
+            bindings: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<A: Clone> Keymap<A> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds `keys`, chords separated by spaces, to `action`. Binding the same keys again
+    /// replaces the old action, keys that start another binding or start with one are an error
+    /// since only the shorter of the two could ever fire
+    pub fn bind(
+        self,
+        keys: &str,
+        action: A,
+        description: impl Into<String>,
+    ) -> Result<Self, crate::Error> {
+        let keys = keys
+            .split_whitespace()
+            .map(KeyChord::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.bind_chords(keys, action, description)
+    }
+
+    pub fn bind_chords(
+        mut self,
+        keys: impl IntoIterator<Item = KeyChord>,
+        action: A,
+        description: impl Into<String>,
+    ) -> Result<Self, crate::Error> {
+        let keys: Vec<_> = keys.into_iter().collect();
+        let shadowed = self.bindings.iter().any(|x| {
+            x.keys != keys && (x.keys.starts_with(&keys) || keys.starts_with(&x.keys))
+        });
+        if keys.is_empty() || shadowed {
+            let keys = keys.iter().map(ToString::to_string).collect::<Vec<_>>();
+            return Err(crate::Error::InvalidKeyBinding(keys.join(" ")));
+        }
+
+        self.bindings.retain(|x| x.keys != keys);
+        self.bindings.push(Binding {
+            keys,
+            action,
+            description: description.into(),
+        });
+        Ok(self)
+    }
+
+    /// Feeds a key from [`crate::api::term::SshTerminal::on_key`], releases are ignored
+    pub fn handle(&mut self, key: &KeyboardEvent) -> KeymapMatch<A> {
+        if key.kind == KeyEventKind::Release {
+            return KeymapMatch::Unbound;
+        }
+
+        let chord = KeyChord::new(key.key, key.modifiers);
+        let continued = !self.pending.is_empty();
+        self.pending.push(chord);
+        match self.lookup() {
+            KeymapMatch::Unbound if continued => {
+                // The sequence went nowhere, give the key a chance on its own
+                self.pending = vec![chord];
+                self.lookup()
+            }
+            rez => rez,
+        }
+    }
+
+    /// Drops a half typed sequence
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Keys of the sequence typed so far
+    pub fn pending(&self) -> &[KeyChord] {
+        &self.pending
+    }
+
+    /// Widget listing every binding with its description
+    pub fn help(&self) -> KeymapHelp<'_, A> {
+        KeymapHelp {
+            keymap: self,
+            title: "Keys",
+        }
+    }
+
+    fn lookup(&mut self) -> KeymapMatch<A> {
+        let mut prefix = false;
+        for binding in &self.bindings {
+            if !binding.keys.starts_with(&self.pending) {
+                continue;
+            }
+            if binding.keys.len() == self.pending.len() {
+                self.pending.clear();
+                return KeymapMatch::Action(binding.action.clone());
+            }
+            prefix = true;
+        }
+
+        if prefix {
+            KeymapMatch::Pending
+        } else {
+            self.pending.clear();
+            KeymapMatch::Unbound
+        }
+    }
+}
+
+/// Help overlay drawn centered over the area it is rendered into, see [`Keymap::help`]
+pub struct KeymapHelp<'a, A> {
+    keymap: &'a Keymap<A>,
+    title: &'a str,
+}
+
+impl<'a, A> KeymapHelp<'a, A> {
+    pub fn title(mut self, title: &'a str) -> Self {
+        self.title = title;
+        self
+    }
+}
+
+impl<A> Widget for KeymapHelp<'_, A> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let rows: Vec<_> = self
+            .keymap
+            .bindings
+            .iter()
+            .map(|binding| {
+                let keys = binding
+                    .keys
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                (keys, binding.description.as_str())
+            })
+            .collect();
+
+        let keys_width = rows.iter().map(|(keys, _)| keys.chars().count()).max().unwrap_or(0);
+        let width = rows
+            .iter()
+            .map(|(_, description)| keys_width + 2 + description.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max(self.title.chars().count())
+            + 4;
+        let width = (width as u16).min(area.width);
+        let height = (rows.len() as u16 + 2).min(area.height);
+        let popup = Rect {
+            x: area.x + (area.width - width) / 2,
+            y: area.y + (area.height - height) / 2,
+            width,
+            height,
+        };
+
+        let lines: Vec<_> = rows
+            .into_iter()
+            .map(|(keys, description)| {
+                Line::from(vec![
+                    Span::styled(
+                        format!("{keys:keys_width$}  "),
+                        Style::default().add_modifier(Modifier::BOLD),
+                    ),
+                    Span::raw(description),
+                ])
+            })
+            .collect();
+
+        Clear.render(popup, buf);
+        Paragraph::new(lines)
+            .block(Block::bordered().title(format!(" {} ", self.title)))
+            .render(popup, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(s: &str) -> KeyChord {
+        s.parse().unwrap()
+    }
+
+    fn press(key: KeyCode, modifiers: Modifiers) -> KeyboardEvent {
+        KeyboardEvent {
+            key,
+            modifiers,
+            kind: KeyEventKind::Press,
+        }
+    }
+
+    #[test]
+    fn parses_chords() {
+        assert_eq!(chord("q"), KeyChord::from('q'));
+        assert_eq!(chord("Ctrl+D"), KeyChord::new(KeyCode::Char('d'), Modifiers::CTRL));
+        assert_eq!(
+            chord("alt+shift+tab"),
+            KeyChord::new(KeyCode::Tab, Modifiers::ALT | Modifiers::SHIFT)
+        );
+        assert_eq!(chord("f12"), KeyChord::from(KeyCode::Function(12)));
+        assert_eq!(chord("space"), KeyChord::from(' '));
+    }
+
+    #[test]
+    fn shifted_letters_parse_like_terminals_report_them() {
+        assert_eq!(chord("shift+a"), KeyChord::from('A'));
+        assert_eq!(chord("shift+A"), KeyChord::from('A'));
+        assert_eq!(chord("ctrl+shift+a"), KeyChord::new(KeyCode::Char('A'), Modifiers::CTRL));
+        assert_eq!(chord("shift+1"), KeyChord::new(KeyCode::Char('1'), Modifiers::SHIFT));
+    }
+
+    #[test]
+    fn trailing_plus_is_the_key() {
+        assert_eq!(chord("+"), KeyChord::from('+'));
+        assert_eq!(chord("ctrl++"), KeyChord::new(KeyCode::Char('+'), Modifiers::CTRL));
+        assert_eq!(
+            chord("ctrl+alt++"),
+            KeyChord::new(KeyCode::Char('+'), Modifiers::CTRL | Modifiers::ALT)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_chords() {
+        for s in ["", "ctrl+", "hyper+a", "f0", "f36", "nope", "ctrl++a"] {
+            assert!(s.parse::<KeyChord>().is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["Ctrl+d", "Alt+Shift+Tab", "F5", "Space", "A", "+", "Ctrl++"] {
+            assert_eq!(chord(s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn sequences() {
+        let mut keymap = Keymap::new()
+            .bind("g g", 1, "Top")
+            .unwrap()
+            .bind("shift+g", 2, "Bottom")
+            .unwrap();
+
+        let g = press(KeyCode::Char('g'), Modifiers::NONE);
+        assert_eq!(keymap.handle(&g), KeymapMatch::Pending);
+        assert_eq!(keymap.handle(&g), KeymapMatch::Action(1));
+        assert_eq!(keymap.handle(&press(KeyCode::Char('G'), Modifiers::NONE)), KeymapMatch::Action(2));
+
+        // A sequence that goes nowhere lets the last key start over
+        assert_eq!(keymap.handle(&g), KeymapMatch::Pending);
+        assert_eq!(keymap.handle(&press(KeyCode::Char('G'), Modifiers::NONE)), KeymapMatch::Action(2));
+        assert!(keymap.pending().is_empty());
+    }
+
+    #[test]
+    fn rejects_bindings_shadowing_each_other() {
+        let keymap = Keymap::new().bind("g g", 1, "Top").unwrap();
+        assert!(matches!(
+            keymap.bind("g", 2, "Go"),
+            Err(crate::Error::InvalidKeyBinding(keys)) if keys == "g"
+        ));
+
+        let keymap = Keymap::new().bind("g", 1, "Go").unwrap();
+        assert!(keymap.bind("g g", 2, "Top").is_err());
+
+        // Same keys replace, siblings are fine
+        let mut keymap = Keymap::new()
+            .bind("g g", 1, "Top")
+            .unwrap()
+            .bind("g e", 2, "End")
+            .unwrap()
+            .bind("g g", 3, "Start")
+            .unwrap();
+        let g = press(KeyCode::Char('g'), Modifiers::NONE);
+        keymap.handle(&g);
+        assert_eq!(keymap.handle(&g), KeymapMatch::Action(3));
+    }
+}
@@ -2,8 +2,12 @@ use std::net::SocketAddr;
 
 use crate::api::term::SshTerminal;
 
+pub mod caps;
+pub mod keymap;
+pub mod task;
 pub mod term;
 pub mod utils;
+pub mod widgets;
 
 /// Session controller for ssh dance
 pub trait ClientHandler: Sync + Send + 'static {
@@ -16,6 +20,10 @@ pub trait ClientHandler: Sync + Send + 'static {
     }
 
     fn new_terminal(&mut self) -> Self::TerminalHandler;
+
+    /// Called when a terminal of this client ends with an error or panics
+    #[allow(unused_variables)]
+    fn on_error(&mut self, error: &crate::Error) {}
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -23,3 +31,16 @@ pub enum Decision {
     Accept,
     Deny,
 }
+
+/// What to do with client input once the session's input queue is full
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum InputOverflow {
+    /// Discard events that do not fit in the queue
+    #[default]
+    Drop,
+    /// Fold the rest of the packet into as few events as possible (typed text becomes a single
+    /// [`termwiz::input::InputEvent::Paste`]) and wait for the terminal to make room
+    Coalesce,
+    /// Disconnect the client
+    Disconnect,
+}
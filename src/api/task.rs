@@ -0,0 +1,54 @@
+use std::future::Future;
+
+use tokio::{
+    select,
+    sync::watch,
+    task::{AbortHandle, JoinHandle},
+};
+
+/// Handle to a task started with [`crate::api::term::EngineRef::spawn`]
+#[derive(Debug, Clone)]
+pub struct TaskHandle(pub(crate) AbortHandle);
+
+impl TaskHandle {
+    /// Cancels the task, its output will never reach the terminal
+    pub fn abort(&self) {
+        self.0.abort();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+}
+
+/// Lifetime of a terminal session, lets work started outside of the engine stop together with it
+#[derive(Debug, Clone)]
+pub struct SessionScope(pub(crate) watch::Receiver<bool>);
+
+impl SessionScope {
+    pub fn is_ended(&self) -> bool {
+        // A dropped sender means the engine is already gone
+        *self.0.borrow() || self.0.has_changed().is_err()
+    }
+
+    /// Resolves once the session ended or terminated
+    pub async fn ended(&self) {
+        let mut rx = self.0.clone();
+        let _ = rx.wait_for(|ended| *ended).await;
+    }
+
+    /// Spawns `fut` on the runtime and aborts it once the session ends
+    pub fn spawn<F>(&self, fut: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let scope = self.clone();
+        tokio::spawn(async move {
+            select! {
+                _ = scope.ended() => None,
+                out = fut => Some(out),
+            }
+        })
+    }
+}
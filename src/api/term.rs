@@ -1,33 +1,78 @@
-use std::num::NonZero;
+use std::{future::Future, num::NonZero, time::Duration};
 
 use ratatui::{
-    layout::Rect,
+    backend::WindowSize,
+    layout::{Rect, Size},
     Frame,
 };
-use termwiz::input::{InputEvent, KeyCode, Modifiers};
+use termwiz::{
+    escape::csi::KittyKeyboardFlags,
+    input::{InputEvent, KeyCode, KeyEvent, Modifiers},
+};
 use tokio::sync::mpsc::UnboundedSender;
 
+pub use termwiz::surface::CursorShape;
+
+use crate::api::{
+    caps::{Capabilities, TerminalModes},
+    task::{SessionScope, TaskHandle},
+};
+
 #[allow(unused_variables)]
 pub trait SshTerminal: Sized + Sync + Send + 'static {
-    type MessageType: Send;
+    type MessageType: Send + 'static;
     const DEFAULT_TPS: Option<NonZero<u8>> = None;
+    /// How the terminal shows up on the client screen
+    const VIEWPORT: ViewportMode = ViewportMode::Fullscreen;
 
     fn on_input(&mut self, engine: &mut impl EngineRef<Self>, input: InputEvent) -> CallbackRez {
         if let InputEvent::Key(key_event) = input {
-            if key_event.key == KeyCode::Char('d') && key_event.modifiers.contains(Modifiers::CTRL)
-            {
-                return CallbackRez::Terminate("See you next time\nSmelly furries".to_string());
+            if let Some(quit) = engine.check_quit(&key_event) {
+                return quit;
             }
         }
         CallbackRez::PushToRenderer
     }
 
-    fn on_resize(
+    /// Called for every key, legacy clients only ever report [`KeyEventKind::Press`].
+    /// Hands presses and repeats to [`SshTerminal::on_input`] unless overridden
+    fn on_key(&mut self, engine: &mut impl EngineRef<Self>, key: KeyboardEvent) -> CallbackRez {
+        if key.kind == KeyEventKind::Release {
+            return CallbackRez::Continue;
+        }
+        self.on_input(
+            engine,
+            InputEvent::Key(KeyEvent {
+                key: key.key,
+                modifiers: key.modifiers,
+            }),
+        )
+    }
+
+    /// Answer to [`EngineRef::enable_keyboard_enhancement`], `false` if the client does not speak
+    /// the kitty keyboard protocol or did not answer in time
+    fn on_keyboard_enhancement(
         &mut self,
         engine: &mut impl EngineRef<Self>,
-        width: u16,
-        height: u16,
+        enabled: bool,
     ) -> CallbackRez {
+        CallbackRez::Continue
+    }
+
+    /// Called with the text of a bracketed paste, newlines included. Pastes over 64 KiB arrive
+    /// in several calls. Hands it to [`SshTerminal::on_input`] as [`InputEvent::Paste`] unless overridden
+    fn on_paste(&mut self, engine: &mut impl EngineRef<Self>, text: String) -> CallbackRez {
+        self.on_input(engine, InputEvent::Paste(text))
+    }
+
+    /// Called when the client window gains or loses focus
+    fn on_focus(&mut self, engine: &mut impl EngineRef<Self>, focused: bool) -> CallbackRez {
+        CallbackRez::Continue
+    }
+
+    /// Called when the client window changes size, `size.pixels` stays zero for clients that do
+    /// not report it
+    fn on_resize(&mut self, engine: &mut impl EngineRef<Self>, size: WindowSize) -> CallbackRez {
         CallbackRez::PushToRenderer
     }
 
@@ -43,6 +88,11 @@ pub trait SshTerminal: Sized + Sync + Send + 'static {
         CallbackRez::Continue
     }
 
+    /// Called when a timer set through [`EngineRef::set_timer`] or [`EngineRef::set_interval`] fires
+    fn on_timer(&mut self, engine: &mut impl EngineRef<Self>, id: &'static str) -> CallbackRez {
+        CallbackRez::Continue
+    }
+
     fn draw(&mut self, frame: &mut Frame<'_>);
 }
 
@@ -50,6 +100,134 @@ pub trait EngineRef<T: SshTerminal> {
     fn terminal_channel(&mut self) -> UnboundedSender<T::MessageType>;
 
     fn current_size(&mut self) -> Rect;
+
+    /// Size of a single cell in pixels, `None` when the client did not report its window in pixels
+    fn cell_size(&mut self) -> Option<Size>;
+
+    /// Terminal modes from the client's pty request, already applied to the keys it reports
+    fn terminal_modes(&mut self) -> TerminalModes;
+
+    /// Runs `fut` in the background without blocking the terminal, its output is handed to
+    /// [`SshTerminal::on_message`] once it is ready.
+    /// The task is aborted when the session ends
+    fn spawn<F>(&mut self, fut: F) -> TaskHandle
+    where
+        F: Future<Output = T::MessageType> + Send + 'static;
+
+    /// Token tied to this session for work that is not started through [`EngineRef::spawn`]
+    fn scope(&mut self) -> SessionScope;
+
+    /// Changes how often [`SshTerminal::on_animation`] is called, also resumes a paused animation
+    fn set_tick_rate(&mut self, period: Duration);
+
+    /// Stops calling [`SshTerminal::on_animation`] until [`EngineRef::resume_animation`]
+    fn pause_animation(&mut self);
+
+    /// Resumes the animation at the last tick rate, does nothing if the terminal never had one
+    fn resume_animation(&mut self);
+
+    /// Calls [`SshTerminal::on_timer`] once after `delay`, replacing any timer with the same id
+    fn set_timer(&mut self, id: &'static str, delay: Duration);
+
+    /// Calls [`SshTerminal::on_timer`] every `period`, replacing any timer with the same id
+    fn set_interval(&mut self, id: &'static str, period: Duration);
+
+    fn cancel_timer(&mut self, id: &'static str);
+
+    fn frame_stats(&mut self) -> FrameStats;
+
+    /// Asks the client to report mouse events as [`InputEvent::Mouse`], turned off again when the
+    /// session ends
+    fn set_mouse_capture(&mut self, capture: MouseCapture);
+
+    /// Changes the look of the cursor, which shows up wherever the last frame put it with
+    /// [`Frame::set_cursor_position`]. The client's own shape comes back when the session ends
+    fn set_cursor_shape(&mut self, shape: CursorShape);
+
+    /// Sets the window and tab title of the client
+    fn set_title(&mut self, title: &str);
+
+    /// Copies `text` to the clipboard of the client's machine. Not every client allows it and
+    /// there is no way to tell whether it worked
+    fn copy_to_clipboard(&mut self, text: &str);
+
+    fn bell(&mut self);
+
+    /// Shows a desktop notification on the client's machine, clients without support drop it
+    fn notify(&mut self, title: &str, body: &str);
+
+    /// Asks the client for the kitty keyboard protocol with `flags`, which brings release events,
+    /// an unambiguous Escape and modifier keys on their own.
+    /// The outcome is reported through [`SshTerminal::on_keyboard_enhancement`]
+    fn enable_keyboard_enhancement(&mut self, flags: KittyKeyboardFlags);
+
+    /// What the client terminal supports, can change early on as the client sends its environment
+    fn capabilities(&mut self) -> Capabilities;
+
+    /// Terminates with the quit message if `key` is the quit binding picked in
+    /// [`crate::SshDanceBuilder::set_quit_binding`]
+    fn check_quit(&mut self, key: &KeyEvent) -> Option<CallbackRez>;
+}
+
+/// Key reported to [`SshTerminal::on_key`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyboardEvent {
+    pub key: KeyCode,
+    pub modifiers: Modifiers,
+    pub kind: KeyEventKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEventKind {
+    Press,
+    /// The key is held down and the client auto repeats it
+    Repeat,
+    Release,
+}
+
+impl From<KeyEvent> for KeyboardEvent {
+    fn from(event: KeyEvent) -> Self {
+        Self {
+            key: event.key,
+            modifiers: event.modifiers,
+            kind: KeyEventKind::Press,
+        }
+    }
+}
+
+/// How a terminal takes over the client screen, see [`SshTerminal::VIEWPORT`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ViewportMode {
+    /// Alternate screen covering the whole window, the client gets its old screen back on exit
+    #[default]
+    Fullscreen,
+    /// That many lines at the bottom of the window, left in the scrollback on exit.
+    /// The cursor of a remote client cannot be looked up, so the lines always start at the bottom
+    Inline(u16),
+    /// Every frame is printed below the previous one as plain lines, nothing is redrawn in place
+    Streaming,
+}
+
+/// Which mouse events the client reports
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MouseCapture {
+    #[default]
+    Off,
+    /// Button presses, releases and the scroll wheel
+    Clicks,
+    /// Like [`MouseCapture::Clicks`] plus motion while a button is held
+    Drag,
+    /// Every motion, even without a button held
+    Motion,
+}
+
+/// Render counters of a single terminal
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames that were actually sent to the client
+    pub drawn: u64,
+    /// Redraw requests folded into a later frame because of the frame rate limit or a slow client
+    pub skipped: u64,
 }
 
 #[derive(Debug)]
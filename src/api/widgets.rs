@@ -0,0 +1,215 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use ratatui::{
+    buffer::Buffer,
+    layout::{Alignment, Rect, Size},
+    style::Color,
+    text::Line,
+    widgets::Widget,
+};
+
+use crate::{
+    api::caps::Graphics,
+    internal::{
+        backend,
+        graphics::{self, GraphicsContext, Placement},
+    },
+};
+
+/// Text that opens `url` when clicked in clients with OSC 8 support, see
+/// [`crate::api::caps::Capabilities::hyperlinks`]. Other clients only show the text
+pub struct Hyperlink<'a> {
+    text: Line<'a>,
+    url: String,
+}
+
+impl<'a> Hyperlink<'a> {
+    pub fn new(text: impl Into<Line<'a>>, url: impl Into<String>) -> Self {
+        // Control characters would end the escape sequence early
+        let url = url.into().chars().filter(|x| !x.is_control()).collect();
+        Self {
+            text: text.into(),
+            url,
+        }
+    }
+}
+
+impl Widget for Hyperlink<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = area.intersection(buf.area);
+        let width = (self.text.width() as u16).min(area.width);
+        let x = match self.text.alignment {
+            Some(Alignment::Center) => (area.width - width) / 2,
+            Some(Alignment::Right) => area.width - width,
+            _ => 0,
+        };
+        self.text.render(area, buf);
+
+        if area.height > 0 {
+            for x in area.x + x..area.x + x + width {
+                if let Some(cell) = buf.cell_mut((x, area.y)) {
+                    backend::set_link(cell, &self.url);
+                }
+            }
+        }
+    }
+}
+
+/// RGBA picture shown by rendering a reference to it, cheap to clone.
+/// Depending on [`crate::api::caps::Capabilities::graphics`] it is drawn with the kitty graphics
+/// protocol, sixels or half blocks. It is scaled to fit the area keeping its aspect ratio
+#[derive(Clone)]
+pub struct Image {
+    id: u32,
+    width: u32,
+    height: u32,
+    pixels: Arc<[u8]>,
+}
+
+impl Image {
+    /// `pixels` holds 4 bytes per pixel, row by row
+    pub fn from_rgba(width: u32, height: u32, pixels: Vec<u8>) -> Result<Self, crate::Error> {
+        if pixels.len() != width as usize * height as usize * 4 {
+            return Err(crate::Error::InvalidImage {
+                width,
+                height,
+                len: pixels.len(),
+            });
+        }
+
+        static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+        Ok(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            width,
+            height,
+            pixels: pixels.into(),
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// RGBA pixels scaled to `size` with nearest neighbour sampling
+    pub(crate) fn resample(&self, size: Size) -> Vec<u8> {
+        let mut out = Vec::with_capacity(size.width as usize * size.height as usize * 4);
+        for y in 0..size.height as u32 {
+            for x in 0..size.width as u32 {
+                out.extend_from_slice(&self.pixel(
+                    x * self.width / size.width as u32,
+                    y * self.height / size.height as u32,
+                ));
+            }
+        }
+        out
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = (y * self.width + x) as usize * 4;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    /// Cells covered by the image and its size in pixels when it is fit into `area`
+    fn fit(&self, area: Rect, cell: Size) -> (Rect, Size) {
+        let (cell_width, cell_height) = (cell.width as u32, cell.height as u32);
+        let (area_width, area_height) = (area.width as u32 * cell_width, area.height as u32 * cell_height);
+
+        let (width, height) = if self.width * area_height > self.height * area_width {
+            (area_width, self.height * area_width / self.width)
+        } else {
+            (self.width * area_height / self.height, area_height)
+        };
+        let (width, height) = (width.max(1), height.max(1));
+
+        let cells = Rect {
+            width: width.div_ceil(cell_width) as u16,
+            height: height.div_ceil(cell_height) as u16,
+            ..area
+        };
+        (cells, Size::new(width as u16, height as u16))
+    }
+
+    /// Two pixels per cell, the upper one in the foreground of `▀` and the lower one behind it
+    fn render_half_blocks(&self, area: Rect, pixels: Size, context: GraphicsContext, buf: &mut Buffer) {
+        let color = |x: u32, y: u32| {
+            if x >= pixels.width as u32 || y >= pixels.height as u32 {
+                return None;
+            }
+            let [r, g, b, a] = self.pixel(
+                x * self.width / pixels.width as u32,
+                y * self.height / pixels.height as u32,
+            );
+            (a >= 128).then_some(Color::Rgb(r, g, b))
+        };
+
+        let (cell_width, cell_height) = (context.cell.width as u32, context.cell.height as u32);
+        for position in area.positions() {
+            // Sample the middle of each half of the cell
+            let x = (position.x - area.x) as u32 * cell_width + cell_width / 2;
+            let y = (position.y - area.y) as u32 * cell_height;
+            let upper = color(x, y + cell_height / 4);
+            let lower = color(x, y + cell_height * 3 / 4);
+
+            let Some(cell) = buf.cell_mut(position) else {
+                continue;
+            };
+            match (upper, lower) {
+                (None, None) => {}
+                // Without unicode a cell only gets a single color
+                (Some(upper), _) if !context.unicode => {
+                    cell.set_symbol(" ").set_bg(upper);
+                }
+                (None, Some(lower)) if !context.unicode => {
+                    cell.set_symbol(" ").set_bg(lower);
+                }
+                (Some(upper), lower) => {
+                    cell.set_symbol("▀").set_fg(upper).set_bg(lower.unwrap_or(Color::Reset));
+                }
+                (None, Some(lower)) => {
+                    cell.set_symbol("▄").set_fg(lower).set_bg(Color::Reset);
+                }
+            }
+        }
+    }
+}
+
+impl Widget for &Image {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = area.intersection(buf.area);
+        if area.is_empty() || self.width == 0 || self.height == 0 {
+            return;
+        }
+
+        let context = graphics::context();
+        let (area, pixels) = self.fit(area, context.cell);
+        if context.graphics == Graphics::HalfBlocks {
+            self.render_half_blocks(area, pixels, context, buf);
+            return;
+        }
+
+        // The picture is drawn over these cells later on, keep ratatui from writing into them
+        for position in area.positions() {
+            if let Some(cell) = buf.cell_mut(position) {
+                cell.reset();
+                cell.set_skip(true);
+            }
+        }
+        graphics::place(Placement {
+            area,
+            pixels,
+            graphics: context.graphics,
+            image: self.clone(),
+        });
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::warn;
+
+/// Receives audit events, set one with [`crate::SshDanceBuilder::set_audit_sink`].
+/// Called from the connection tasks so keep it quick
+pub trait AuditSink: Send + Sync + 'static {
+    fn record(&self, event: &AuditEvent);
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub time: SystemTime,
+    /// Unique per TCP connection, all events of one client share it
+    pub connection: u64,
+    pub addr: Option<SocketAddr>,
+    pub kind: AuditEventKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuditEventKind {
+    ConnectionOpened,
+    AuthAttempt {
+        user: String,
+        method: &'static str,
+        accepted: bool,
+    },
+    PtyGranted {
+        term: String,
+        width: u32,
+        height: u32,
+    },
+    ExecRequest {
+        command: String,
+    },
+    SubsystemRequest {
+        name: String,
+    },
+    /// A terminal session finished, `reason` says whether it terminated, errored or the client left
+    SessionEnded {
+        duration: Duration,
+        reason: String,
+    },
+    ConnectionClosed {
+        duration: Duration,
+    },
+}
+
+impl AuditEvent {
+    /// Encodes the event as a single line JSON object
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let _ = write!(out, "\"time\":{time},\"connection\":{}", self.connection);
+        match self.addr {
+            Some(addr) => field_str(&mut out, "addr", &addr.to_string()),
+            None => out.push_str(",\"addr\":null"),
+        }
+
+        match &self.kind {
+            AuditEventKind::ConnectionOpened => field_str(&mut out, "event", "connection_opened"),
+            AuditEventKind::AuthAttempt {
+                user,
+                method,
+                accepted,
+            } => {
+                field_str(&mut out, "event", "auth_attempt");
+                field_str(&mut out, "user", user);
+                field_str(&mut out, "method", method);
+                let _ = write!(out, ",\"accepted\":{accepted}");
+            }
+            AuditEventKind::PtyGranted {
+                term,
+                width,
+                height,
+            } => {
+                field_str(&mut out, "event", "pty_granted");
+                field_str(&mut out, "term", term);
+                let _ = write!(out, ",\"width\":{width},\"height\":{height}");
+            }
+            AuditEventKind::ExecRequest { command } => {
+                field_str(&mut out, "event", "exec_request");
+                field_str(&mut out, "command", command);
+            }
+            AuditEventKind::SubsystemRequest { name } => {
+                field_str(&mut out, "event", "subsystem_request");
+                field_str(&mut out, "name", name);
+            }
+            AuditEventKind::SessionEnded { duration, reason } => {
+                field_str(&mut out, "event", "session_ended");
+                let _ = write!(out, ",\"duration\":{}", duration.as_secs_f64());
+                field_str(&mut out, "reason", reason);
+            }
+            AuditEventKind::ConnectionClosed { duration } => {
+                field_str(&mut out, "event", "connection_closed");
+                let _ = write!(out, ",\"duration\":{}", duration.as_secs_f64());
+            }
+        }
+
+        out.push('}');
+        out
+    }
+}
+
+fn field_str(out: &mut String, key: &str, value: &str) {
+    let _ = write!(out, ",\"{key}\":\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Appends every event as a JSON object on its own line. Lines are written by a thread of its own,
+/// `record` only hands them over
+pub struct JsonLinesAuditSink {
+    lines: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl JsonLinesAuditSink {
+    /// Opens `path` for appending, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, crate::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("sshdance-audit".to_string())
+            .spawn(move || write_lines(BufWriter::new(file), receiver))?;
+        Ok(Self {
+            lines: Some(sender),
+            writer: Some(writer),
+        })
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, event: &AuditEvent) {
+        let line = event.to_json();
+        if self.lines.as_ref().is_none_or(|lines| lines.send(line).is_err()) {
+            warn!("Audit writer is gone, dropping event");
+        }
+    }
+}
+
+impl Drop for JsonLinesAuditSink {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer flush what is left and stop
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes lines as they come in and flushes whenever it runs out of them
+fn write_lines(mut file: BufWriter<File>, lines: Receiver<String>) {
+    while let Ok(line) = lines.recv() {
+        let mut rez = writeln!(file, "{line}");
+        while let Ok(line) = lines.try_recv() {
+            rez = rez.and_then(|_| writeln!(file, "{line}"));
+        }
+        if let Err(err) = rez.and_then(|_| file.flush()) {
+            warn!("Could not write audit events {err:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: AuditEventKind) -> AuditEvent {
+        AuditEvent {
+            time: UNIX_EPOCH + Duration::from_millis(1500),
+            connection: 7,
+            addr: Some("127.0.0.1:2222".parse().unwrap()),
+            kind,
+        }
+    }
+
+    #[test]
+    fn encodes_fields() {
+        let json = event(AuditEventKind::PtyGranted {
+            term: "xterm".to_string(),
+            width: 80,
+            height: 24,
+        })
+        .to_json();
+        assert_eq!(
+            json,
+            r#"{"time":1.5,"connection":7,"addr":"127.0.0.1:2222","event":"pty_granted","term":"xterm","width":80,"height":24}"#
+        );
+    }
+
+    #[test]
+    fn escapes_strings() {
+        let json = event(AuditEventKind::ExecRequest {
+            command: "echo \"hi\"\\\n\t\x07é".to_string(),
+        })
+        .to_json();
+        assert!(json.ends_with(r#""command":"echo \"hi\"\\\n\t\u0007é"}"#), "{json}");
+    }
+
+    #[test]
+    fn missing_addr_is_null() {
+        let mut event = event(AuditEventKind::ConnectionOpened);
+        event.addr = None;
+        assert!(event.to_json().contains(r#""addr":null,"event":"connection_opened""#));
+    }
+
+    #[test]
+    fn sink_writes_every_line_before_drop_returns() {
+        let path = std::env::temp_dir().join(format!("sshdance-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let sink = JsonLinesAuditSink::open(&path).unwrap();
+        sink.record(&event(AuditEventKind::ConnectionOpened));
+        sink.record(&event(AuditEventKind::ConnectionClosed {
+            duration: Duration::from_secs(2),
+        }));
+        drop(sink);
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("connection_opened"));
+        assert!(lines[1].ends_with(r#""event":"connection_closed","duration":2}"#));
+    }
+}
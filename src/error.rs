@@ -14,6 +14,15 @@ pub enum Error {
     #[error("Session closed")]
     SessionClosed,
 
+    #[error("Terminal panicked: {0}")]
+    TerminalPanic(String),
+
+    #[error("Invalid key binding {0:?}")]
+    InvalidKeyBinding(String),
+
+    #[error("Image of {width}x{height} needs {} bytes of RGBA, got {len}", *width as usize * *height as usize * 4)]
+    InvalidImage { width: u32, height: u32, len: usize },
+
     #[error("Enocuntered russh error {0}")]
     RusshError(#[from] russh::Error),
 
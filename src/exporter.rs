@@ -0,0 +1,102 @@
+use std::{fmt::Write as _, net::SocketAddr};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+use crate::registry::SessionRegistry;
+
+/// Serves the statistics of `registry` in the Prometheus text format on every path of `addr`
+pub(crate) async fn serve(addr: SocketAddr, registry: SessionRegistry) -> Result<(), crate::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on {addr}");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!("Metrics scrape from {peer}");
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &registry).await {
+                warn!("Encountered an error while serving metrics {err:?}");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, registry: &SessionRegistry) -> std::io::Result<()> {
+    // We answer every request the same way, just make sure the request made it in before replying
+    let mut request = [0u8; 4096];
+    let mut read = 0;
+    while read < request.len() && !request[..read].windows(4).any(|x| x == b"\r\n\r\n") {
+        let n = stream.read(&mut request[read..]).await?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+
+    let body = render(registry);
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn render(registry: &SessionRegistry) -> String {
+    let stats = registry.server_stats();
+    let mut out = String::new();
+
+    metric(&mut out, "sshdance_active_sessions", "gauge", "Terminal sessions currently running");
+    let _ = writeln!(out, "sshdance_active_sessions {}", stats.active_sessions);
+
+    metric(&mut out, "sshdance_connections_total", "counter", "Accepted TCP connections");
+    let _ = writeln!(out, "sshdance_connections_total {}", stats.connections);
+
+    metric(
+        &mut out,
+        "sshdance_rejected_connections_total",
+        "counter",
+        "Session channels turned down by the client handler",
+    );
+    let _ = writeln!(out, "sshdance_rejected_connections_total {}", stats.rejected_connections);
+
+    metric(&mut out, "sshdance_auth_failures_total", "counter", "Failed authentication attempts");
+    for (method, failures) in &stats.auth_failures {
+        let _ = writeln!(out, "sshdance_auth_failures_total{{method=\"{method}\"}} {failures}");
+    }
+
+    metric(&mut out, "sshdance_bytes_in_total", "counter", "Bytes received from clients");
+    let _ = writeln!(out, "sshdance_bytes_in_total {}", stats.bytes_in);
+
+    metric(&mut out, "sshdance_bytes_out_total", "counter", "Bytes sent to clients");
+    let _ = writeln!(out, "sshdance_bytes_out_total {}", stats.bytes_out);
+
+    metric(
+        &mut out,
+        "sshdance_draw_seconds",
+        "histogram",
+        "Time spent rendering a single frame",
+    );
+    for (bound, count) in &stats.draw_time.buckets {
+        let _ = writeln!(
+            out,
+            "sshdance_draw_seconds_bucket{{le=\"{}\"}} {count}",
+            bound.as_secs_f64()
+        );
+    }
+    let _ = writeln!(out, "sshdance_draw_seconds_bucket{{le=\"+Inf\"}} {}", stats.draw_time.count);
+    let _ = writeln!(out, "sshdance_draw_seconds_sum {}", stats.draw_time.sum.as_secs_f64());
+    let _ = writeln!(out, "sshdance_draw_seconds_count {}", stats.draw_time.count);
+
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
@@ -0,0 +1,610 @@
+use std::{
+    io::{self, Write},
+    sync::Arc,
+};
+
+use ratatui::{
+    backend::{Backend, ClearType, WindowSize},
+    buffer::Cell,
+    layout::{Position, Size},
+    style::{Color, Modifier},
+};
+use termwiz::{
+    caps::Capabilities,
+    cell::{unicode_column_width, Blink, CellAttributes, Intensity, Underline},
+    color::ColorAttribute,
+    escape::{
+        csi::{Cursor, DecPrivateModeCode, Edit, EraseInDisplay, EraseInLine, CSI},
+        OneBased,
+    },
+    hyperlink::Hyperlink,
+    render::{terminfo::TerminfoRenderer, RenderTty},
+    surface::Change,
+};
+
+use crate::{
+    api::caps::Graphics,
+    internal::{
+        escape,
+        graphics::{self, Placement},
+    },
+};
+
+/// Starts the link a cell carries after its symbol, it has no other use so it never shows up in text
+const LINK_START: char = '\u{e0001}';
+
+/// Turns `cell` into part of a link to `url`. The url is appended to the symbol as invisible tag
+/// characters, which makes ratatui redraw the cell whenever the link changes
+pub fn set_link(cell: &mut Cell, url: &str) {
+    let (text, _) = split_link(cell.symbol());
+    let mut symbol = String::with_capacity(text.len() + 4 * (url.len() + 1));
+    symbol.push_str(text);
+    symbol.push(LINK_START);
+    for byte in url.bytes() {
+        if byte.is_ascii_graphic() {
+            symbol.push(tag(byte));
+        } else {
+            // Tags only exist for printable ASCII
+            for byte in format!("%{byte:02X}").bytes() {
+                symbol.push(tag(byte));
+            }
+        }
+    }
+    cell.set_symbol(&symbol);
+}
+
+fn tag(byte: u8) -> char {
+    char::from_u32(0xe0000 + byte as u32).expect("tags cover ASCII")
+}
+
+/// The text of a cell symbol and the url of the link [`set_link`] put into it
+fn split_link(symbol: &str) -> (&str, Option<String>) {
+    match symbol.split_once(LINK_START) {
+        Some((text, url)) => {
+            let url = url
+                .chars()
+                .filter_map(|x| (x as u32).checked_sub(0xe0000).filter(|x| *x < 0x80))
+                .map(|x| x as u8 as char)
+                .collect();
+            (text, Some(url))
+        }
+        None => (symbol, None),
+    }
+}
+
+/// Ratatui backend that renders through the terminfo entry of the client's `TERM`
+pub struct TermwizBackend<W: Write> {
+    writer: W,
+    renderer: TerminfoRenderer,
+    size: Size,
+    // Size of the window in pixels, zero when the client did not say
+    pixels: Size,
+    // Where the last change left the cursor, a remote terminal cannot be asked synchronously
+    cursor: Position,
+    // A synchronized update was started and gets ended by the next flush
+    updating: bool,
+    // Frames are printed as plain lines below each other instead of drawn in place
+    streaming: bool,
+    // Images on screen with the kitty image id they were sent with, only sent again when they change
+    images: Vec<(Placement, u32)>,
+    next_kitty_id: u32,
+}
+
+/// Lets the renderer write straight into the backend's writer
+struct Tty<'a, W: Write> {
+    writer: &'a mut W,
+    size: Size,
+}
+
+impl<W: Write> Write for Tty<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> RenderTty for Tty<'_, W> {
+    fn get_size_in_cells(&mut self) -> termwiz::Result<(usize, usize)> {
+        Ok((self.size.width as usize, self.size.height as usize))
+    }
+}
+
+impl<W: Write> TermwizBackend<W> {
+    pub fn new(writer: W, caps: Capabilities, size: WindowSize) -> Self {
+        Self {
+            writer,
+            renderer: TerminfoRenderer::new(caps),
+            size: size.columns_rows,
+            pixels: size.pixels,
+            cursor: Position::ORIGIN,
+            updating: false,
+            streaming: false,
+            images: Vec::new(),
+            next_kitty_id: 1,
+        }
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Switches to the terminfo entry of a `TERM` the client sent later on
+    pub fn set_caps(&mut self, caps: Capabilities) {
+        self.renderer = TerminfoRenderer::new(caps);
+    }
+
+    /// The size reported to ratatui, keep it in sync with the client window
+    pub fn set_size(&mut self, size: WindowSize) {
+        self.pixels = size.pixels;
+        let size = size.columns_rows;
+        self.size = size;
+        self.cursor.x = self.cursor.x.min(size.width.saturating_sub(1));
+        self.cursor.y = self.cursor.y.min(size.height.saturating_sub(1));
+    }
+
+    /// Prints every frame below the previous one without moving the cursor or clearing anything
+    pub fn set_streaming(&mut self, streaming: bool) {
+        self.streaming = streaming;
+    }
+
+    /// Makes the client hold off showing anything until the next flush, so a frame split over
+    /// several packets still appears at once
+    pub fn begin_update(&mut self) -> io::Result<()> {
+        if !self.updating {
+            self.updating = true;
+            self.dec_mode(DecPrivateModeCode::SynchronizedOutput, true)?;
+        }
+        Ok(())
+    }
+
+    fn dec_mode(&mut self, code: DecPrivateModeCode, enable: bool) -> io::Result<()> {
+        let mut out = String::new();
+        escape::dec_mode(&mut out, code, enable);
+        self.writer.write_all(out.as_bytes())
+    }
+
+    /// Lays the cells out with line breaks and spaces, rows without content still take up a line
+    fn draw_streaming<'a>(
+        &mut self,
+        content: impl Iterator<Item = (u16, u16, &'a Cell)>,
+    ) -> io::Result<()> {
+        let mut links = Links::default();
+        let mut changes = Vec::new();
+        let mut attributes = CellAttributes::default();
+        let (mut column, mut row) = (0, 0);
+
+        for (x, y, cell) in content {
+            if y > row || x > column {
+                // Keep backgrounds from bleeding into the gap
+                set_attributes(&mut changes, &mut attributes, CellAttributes::default());
+            }
+            if y > row {
+                changes.push(Change::Text("\r\n".repeat((y - row) as usize)));
+                (column, row) = (0, y);
+            }
+            if x > column {
+                changes.push(Change::Text(" ".repeat((x - column) as usize)));
+            }
+
+            let (text, cell_attributes) = links.attributes(cell);
+            set_attributes(&mut changes, &mut attributes, cell_attributes);
+            changes.push(Change::Text(text.to_string()));
+            column = x + unicode_column_width(text, None).max(1) as u16;
+        }
+
+        if !changes.is_empty() {
+            set_attributes(&mut changes, &mut attributes, CellAttributes::default());
+            changes.push(Change::Text("\r\n".to_string()));
+        }
+        self.render(&changes)
+    }
+
+    /// Sends images that are new or moved since the last frame and takes down the ones that are gone
+    fn draw_images(&mut self, placements: Vec<Placement>) -> io::Result<()> {
+        let mut shown = std::mem::take(&mut self.images);
+        let mut out = String::new();
+        for placement in placements {
+            if let Some(index) = shown.iter().position(|(x, _)| *x == placement) {
+                self.images.push(shown.swap_remove(index));
+                continue;
+            }
+
+            out.clear();
+            // Every placement gets an image of its own, sending an image again replaces its placements
+            let id = self.next_kitty_id;
+            match placement.graphics {
+                Graphics::Kitty => {
+                    self.next_kitty_id = self.next_kitty_id.checked_add(1).unwrap_or(1);
+                    graphics::kitty(&mut out, &placement, id);
+                }
+                Graphics::Sixel => graphics::sixel(&mut out, &placement),
+                Graphics::HalfBlocks => continue,
+            }
+            self.set_cursor_position(placement.area.as_position())?;
+            self.writer.write_all(out.as_bytes())?;
+            self.images.push((placement, id));
+        }
+
+        out.clear();
+        for (placement, id) in shown {
+            if placement.graphics == Graphics::Kitty {
+                graphics::kitty_delete(&mut out, id);
+            }
+        }
+        self.writer.write_all(out.as_bytes())
+    }
+
+    /// Called when the screen gets cleared, which takes sixels with it
+    fn forget_images(&mut self) -> io::Result<()> {
+        let mut out = String::new();
+        for (placement, id) in self.images.drain(..) {
+            if placement.graphics == Graphics::Kitty {
+                graphics::kitty_delete(&mut out, id);
+            }
+        }
+        self.writer.write_all(out.as_bytes())
+    }
+
+    fn render(&mut self, changes: &[Change]) -> io::Result<()> {
+        let mut tty = Tty {
+            writer: &mut self.writer,
+            size: self.size,
+        };
+        self.renderer
+            .render_to(changes, &mut tty)
+            .map_err(io::Error::other)
+    }
+}
+
+impl<W: Write> Write for TermwizBackend<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Backend for TermwizBackend<W> {
+    type Error = io::Error;
+
+    fn draw<'a, I>(&mut self, content: I) -> Result<(), Self::Error>
+    where
+        I: Iterator<Item = (u16, u16, &'a Cell)>,
+    {
+        if self.streaming {
+            return self.draw_streaming(content);
+        }
+
+        let mut links = Links::default();
+        let mut changes = Vec::new();
+        let mut last: Option<Position> = None;
+        let mut attributes: Option<CellAttributes> = None;
+
+        for (x, y, cell) in content {
+            // Only jump when the cell does not directly follow the last one
+            if !last.is_some_and(|last| x == last.x + 1 && y == last.y) {
+                changes.push(cursor_position(Position { x, y }));
+            }
+            last = Some(Position { x, y });
+
+            let (text, cell_attributes) = links.attributes(cell);
+            if attributes.as_ref() != Some(&cell_attributes) {
+                changes.push(Change::AllAttributes(cell_attributes.clone()));
+                attributes = Some(cell_attributes);
+            }
+            changes.push(Change::Text(text.to_string()));
+        }
+
+        if let Some(last) = last {
+            changes.push(Change::AllAttributes(CellAttributes::default()));
+            self.cursor = Position {
+                x: last.x + 1,
+                y: last.y,
+            };
+        }
+        self.render(&changes)?;
+        self.draw_images(graphics::take_placements())
+    }
+
+    fn append_lines(&mut self, n: u16) -> Result<(), Self::Error> {
+        for _ in 0..n {
+            self.writer.write_all(b"\n")?;
+        }
+        self.cursor.y = (self.cursor.y + n).min(self.size.height.saturating_sub(1));
+        Ok(())
+    }
+
+    fn hide_cursor(&mut self) -> Result<(), Self::Error> {
+        if self.streaming {
+            return Ok(());
+        }
+        self.dec_mode(DecPrivateModeCode::ShowCursor, false)
+    }
+
+    fn show_cursor(&mut self) -> Result<(), Self::Error> {
+        if self.streaming {
+            return Ok(());
+        }
+        // The terminfo entry of xterm also turns blinking off, which undoes the cursor shape
+        self.dec_mode(DecPrivateModeCode::ShowCursor, true)
+    }
+
+    fn get_cursor_position(&mut self) -> Result<Position, Self::Error> {
+        Ok(self.cursor)
+    }
+
+    fn set_cursor_position<P: Into<Position>>(&mut self, position: P) -> Result<(), Self::Error> {
+        let position = position.into();
+        if self.streaming {
+            return Ok(());
+        }
+        self.cursor = position;
+        self.render(&[cursor_position(position)])
+    }
+
+    fn clear(&mut self) -> Result<(), Self::Error> {
+        if self.streaming {
+            return Ok(());
+        }
+        self.forget_images()?;
+        self.cursor = Position::ORIGIN;
+        self.render(&[Change::ClearScreen(ColorAttribute::Default)])
+    }
+
+    fn clear_region(&mut self, clear_type: ClearType) -> Result<(), Self::Error> {
+        if self.streaming {
+            return Ok(());
+        }
+        self.forget_images()?;
+        let edit = match clear_type {
+            ClearType::All => return self.clear(),
+            ClearType::AfterCursor => {
+                return self.render(&[Change::ClearToEndOfScreen(ColorAttribute::Default)])
+            }
+            ClearType::UntilNewLine => {
+                return self.render(&[Change::ClearToEndOfLine(ColorAttribute::Default)])
+            }
+            ClearType::BeforeCursor => Edit::EraseInDisplay(EraseInDisplay::EraseToStartOfDisplay),
+            ClearType::CurrentLine => Edit::EraseInLine(EraseInLine::EraseLine),
+        };
+        write!(self.writer, "{}", CSI::Edit(edit))
+    }
+
+    fn size(&self) -> Result<Size, Self::Error> {
+        Ok(self.size)
+    }
+
+    fn window_size(&mut self) -> Result<WindowSize, Self::Error> {
+        Ok(WindowSize {
+            columns_rows: self.size,
+            pixels: self.pixels,
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        if self.updating {
+            self.updating = false;
+            self.dec_mode(DecPrivateModeCode::SynchronizedOutput, false)?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// Hands out the same [`Hyperlink`] to neighbouring cells of a link
+#[derive(Default)]
+struct Links {
+    last: Option<Arc<Hyperlink>>,
+}
+
+impl Links {
+    /// Text to print for `cell` and the attributes to print it with
+    fn attributes<'a>(&mut self, cell: &'a Cell) -> (&'a str, CellAttributes) {
+        let mut attributes = cell_attributes(cell);
+        let (text, url) = split_link(cell.symbol());
+        if let Some(url) = url {
+            let link = match &self.last {
+                Some(link) if link.uri() == url => link.clone(),
+                _ => Arc::new(Hyperlink::new(url)),
+            };
+            attributes.set_hyperlink(Some(link.clone()));
+            self.last = Some(link);
+        }
+        (text, attributes)
+    }
+}
+
+fn set_attributes(changes: &mut Vec<Change>, current: &mut CellAttributes, next: CellAttributes) {
+    if *current != next {
+        changes.push(Change::AllAttributes(next.clone()));
+        *current = next;
+    }
+}
+
+/// Moves with a plain CUP, termwiz swaps row and column when the terminfo entry is missing
+fn cursor_position(position: Position) -> Change {
+    let position = Cursor::Position {
+        line: OneBased::from_zero_based(position.y as u32),
+        col: OneBased::from_zero_based(position.x as u32),
+    };
+    Change::Text(CSI::Cursor(position).to_string())
+}
+
+fn cell_attributes(cell: &Cell) -> CellAttributes {
+    let mut attributes = CellAttributes::default();
+    attributes
+        .set_foreground(color(cell.fg))
+        .set_background(color(cell.bg))
+        .set_underline_color(color(cell.underline_color));
+
+    let modifier = cell.modifier;
+    if modifier.contains(Modifier::BOLD) {
+        attributes.set_intensity(Intensity::Bold);
+    } else if modifier.contains(Modifier::DIM) {
+        attributes.set_intensity(Intensity::Half);
+    }
+    if modifier.contains(Modifier::UNDERLINED) {
+        attributes.set_underline(Underline::Single);
+    }
+    if modifier.contains(Modifier::RAPID_BLINK) {
+        attributes.set_blink(Blink::Rapid);
+    } else if modifier.contains(Modifier::SLOW_BLINK) {
+        attributes.set_blink(Blink::Slow);
+    }
+    attributes
+        .set_italic(modifier.contains(Modifier::ITALIC))
+        .set_reverse(modifier.contains(Modifier::REVERSED))
+        .set_invisible(modifier.contains(Modifier::HIDDEN))
+        .set_strikethrough(modifier.contains(Modifier::CROSSED_OUT));
+    attributes
+}
+
+fn color(color: Color) -> ColorAttribute {
+    let index = match color {
+        Color::Reset => return ColorAttribute::Default,
+        Color::Rgb(r, g, b) => return ColorAttribute::TrueColorWithDefaultFallback((r, g, b).into()),
+        Color::Indexed(i) => i,
+        Color::Black => 0,
+        Color::Red => 1,
+        Color::Green => 2,
+        Color::Yellow => 3,
+        Color::Blue => 4,
+        Color::Magenta => 5,
+        Color::Cyan => 6,
+        Color::Gray => 7,
+        Color::DarkGray => 8,
+        Color::LightRed => 9,
+        Color::LightGreen => 10,
+        Color::LightYellow => 11,
+        Color::LightBlue => 12,
+        Color::LightMagenta => 13,
+        Color::LightCyan => 14,
+        Color::White => 15,
+    };
+    ColorAttribute::PaletteIndex(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{
+        buffer::Buffer,
+        layout::Rect,
+        text::{Span, Text},
+        widgets::Widget,
+        Terminal, TerminalOptions, Viewport,
+    };
+    use termwiz::caps::ProbeHints;
+
+    use super::*;
+    use crate::api::widgets::Hyperlink;
+
+    fn terminal() -> Terminal<TermwizBackend<Vec<u8>>> {
+        let hints = ProbeHints::default()
+            .term(Some("xterm-256color".to_string()))
+            .hyperlinks(Some(true));
+        let size = WindowSize {
+            columns_rows: Size::new(20, 2),
+            pixels: Size::default(),
+        };
+        let backend = TermwizBackend::new(Vec::new(), Capabilities::new_with_hints(hints).unwrap(), size);
+        let area = Rect::from((Position::ORIGIN, size.columns_rows));
+        Terminal::with_options(backend, TerminalOptions { viewport: Viewport::Fixed(area) }).unwrap()
+    }
+
+    fn draw(term: &mut Terminal<TermwizBackend<Vec<u8>>>, link: Option<&str>) -> String {
+        term.draw(|frame| match link {
+            Some(url) => frame.render_widget(Hyperlink::new("docs", url), frame.area()),
+            None => frame.render_widget(Text::raw("docs"), frame.area()),
+        })
+        .unwrap();
+        String::from_utf8(std::mem::take(term.backend_mut().writer_mut())).unwrap()
+    }
+
+    #[test]
+    fn link_survives_the_symbol() {
+        let mut cell = Cell::new("é");
+        set_link(&mut cell, "https://example.com/a b");
+        assert_eq!(Span::raw(cell.symbol()).width(), 1);
+        let (text, url) = split_link(cell.symbol());
+        assert_eq!(text, "é");
+        assert_eq!(url.as_deref(), Some("https://example.com/a%20b"));
+
+        // Linking again replaces the old url
+        set_link(&mut cell, "x");
+        assert_eq!(split_link(cell.symbol()), ("é", Some("x".to_string())));
+        assert_eq!(split_link("plain"), ("plain", None));
+    }
+
+    #[test]
+    fn links_are_redrawn_when_they_change() {
+        let mut term = terminal();
+        assert!(!draw(&mut term, None).contains("\x1b]8"));
+
+        // Same text as before, only the link is new
+        let out = draw(&mut term, Some("https://a.example"));
+        assert!(out.contains("https://a.example"), "{out:?}");
+        assert!(out.contains("docs"));
+        assert!(!out.contains('\u{e0001}'));
+
+        let out = draw(&mut term, Some("https://b.example"));
+        assert!(out.contains("https://b.example"), "{out:?}");
+
+        let out = draw(&mut term, None);
+        assert!(out.contains("docs") && !out.contains("example"), "{out:?}");
+    }
+
+    #[test]
+    fn same_image_placed_twice() {
+        let mut term = terminal();
+        graphics::set_context(graphics::GraphicsContext {
+            graphics: Graphics::Kitty,
+            cell: Size::new(8, 16),
+            unicode: true,
+        });
+        let image = crate::api::widgets::Image::from_rgba(1, 2, vec![255; 8]).unwrap();
+        let mut draw = |count: u16| {
+            term.draw(|frame| {
+                for x in 0..count {
+                    frame.render_widget(&image, Rect::new(x * 2, 0, 1, 2));
+                }
+            })
+            .unwrap();
+            String::from_utf8(std::mem::take(term.backend_mut().writer_mut())).unwrap()
+        };
+
+        let out = draw(2);
+        assert!(out.contains("a=T,f=32,s=8,v=16,i=1,") && out.contains("a=T,f=32,s=8,v=16,i=2,"), "{out:?}");
+        // Nothing changed, nothing is sent again
+        assert!(!draw(2).contains("\x1b_G"));
+        let out = draw(1);
+        assert!(out.contains("\x1b_Ga=d,d=I,i=2,q=2") && !out.contains("a=T"), "{out:?}");
+    }
+
+    #[test]
+    fn images_placed_outside_a_draw_are_dropped() {
+        let mut term = terminal();
+        let image = crate::api::widgets::Image::from_rgba(1, 2, vec![255; 8]).unwrap();
+        let context = graphics::GraphicsContext {
+            graphics: Graphics::Kitty,
+            cell: Size::new(8, 16),
+            unicode: true,
+        };
+        graphics::set_context(context);
+        // Rendered into a scratch buffer, as a panicking draw would leave it
+        let area = Rect::new(0, 0, 1, 2);
+        (&image).render(area, &mut Buffer::empty(area));
+
+        graphics::set_context(context);
+        term.draw(|_| {}).unwrap();
+        let out = String::from_utf8(std::mem::take(term.backend_mut().writer_mut())).unwrap();
+        assert!(!out.contains("\x1b_G"), "{out:?}");
+    }
+}
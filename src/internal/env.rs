@@ -0,0 +1,287 @@
+use std::{collections::HashMap, io};
+
+use termwiz::caps::{ColorLevel, ProbeHints};
+use tracing::debug;
+
+use crate::api::caps::{Capabilities, Graphics, TerminalModes};
+
+/// Variables from client env requests that say something about the terminal, everything else is dropped
+const KNOWN_VARS: [&str; 10] = [
+    "TERM",
+    "COLORTERM",
+    "TERM_PROGRAM",
+    "TERM_PROGRAM_VERSION",
+    "LANG",
+    "LC_ALL",
+    "LC_CTYPE",
+    "NO_COLOR",
+    "VTE_VERSION",
+    "WT_SESSION",
+];
+
+/// Terminals known to understand hyperlinks and synchronized output, matched against `TERM`
+const MODERN_TERMS: [&str; 6] = ["kitty", "wezterm", "foot", "alacritty", "ghostty", "contour"];
+
+/// Same as [`MODERN_TERMS`] for `TERM_PROGRAM`
+const MODERN_PROGRAMS: [&str; 5] = ["iTerm.app", "WezTerm", "vscode", "ghostty", "Tabby"];
+
+/// Terminals that speak the kitty graphics protocol, matched against `TERM` and `TERM_PROGRAM`
+const KITTY_GRAPHICS: [&str; 4] = ["kitty", "ghostty", "WezTerm", "wezterm"];
+
+/// Terminals that show sixels, matched against `TERM` and `TERM_PROGRAM`
+const SIXEL_GRAPHICS: [&str; 5] = ["foot", "mlterm", "contour", "iTerm.app", "mintty"];
+
+/// Variables that change what [`ClientEnv::probe`] finds, the locale only matters for unicode
+const PROBED_VARS: [&str; 7] = [
+    "TERM",
+    "COLORTERM",
+    "TERM_PROGRAM",
+    "TERM_PROGRAM_VERSION",
+    "NO_COLOR",
+    "VTE_VERSION",
+    "WT_SESSION",
+];
+
+/// Terminal related part of the environment a client sent for its session
+#[derive(Debug, Clone, Default)]
+pub struct ClientEnv {
+    vars: HashMap<&'static str, String>,
+    modes: TerminalModes,
+    // What the terminfo database says about the client, dropped whenever one of `PROBED_VARS` changes
+    probe: Option<Probe>,
+}
+
+#[derive(Debug, Clone)]
+struct Probe {
+    color: ColorLevel,
+    sync: bool,
+    render_caps: termwiz::caps::Capabilities,
+}
+
+impl ClientEnv {
+    /// Whether `name` says anything about the terminal
+    pub fn accepts(name: &str) -> bool {
+        KNOWN_VARS.contains(&name)
+    }
+
+    /// Keeps `value` if `name` is a variable we care about
+    pub fn set(&mut self, name: &str, value: &str) {
+        let Some(name) = KNOWN_VARS.iter().find(|x| **x == name) else {
+            debug!("Ignoring client env {name}");
+            return;
+        };
+
+        if self.vars.get(name).is_some_and(|x| x == value) {
+            return;
+        }
+        self.vars.insert(name, value.to_string());
+        if PROBED_VARS.contains(name) {
+            self.probe = None;
+        }
+    }
+
+    pub fn set_modes(&mut self, modes: TerminalModes) {
+        self.modes = modes;
+    }
+
+    pub fn modes(&self) -> TerminalModes {
+        self.modes
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    fn hints(&self) -> ProbeHints {
+        let hints = ProbeHints::default()
+            .term(Some(self.get("TERM").unwrap_or_default().to_string()))
+            .colorterm(self.get("COLORTERM").map(str::to_string))
+            .term_program(self.get("TERM_PROGRAM").map(str::to_string))
+            .term_program_version(self.get("TERM_PROGRAM_VERSION").map(str::to_string));
+        if self.get("NO_COLOR").is_some_and(|x| !x.is_empty()) {
+            hints.color_level(Some(ColorLevel::MonoChrome))
+        } else {
+            hints
+        }
+    }
+
+    /// Looks `TERM` up in the terminfo database of the server, the client's one is out of reach.
+    /// That is blocking file IO, so it only happens again once the variables it depends on changed
+    fn probe(&mut self) -> io::Result<&Probe> {
+        if self.probe.is_none() {
+            // Colors are left alone as they are downsampled before rendering.
+            // Hyperlinks follow our own detection, the terminfo database knows nothing about them
+            let hints = self
+                .hints()
+                .color_level(Some(ColorLevel::TrueColor))
+                .hyperlinks(Some(self.hyperlinks()));
+            let render_caps = termwiz::caps::Capabilities::new_with_hints(hints).map_err(io::Error::other)?;
+
+            // Same lookup without the forced color level, handing over the database so it is not read twice
+            let db = render_caps.terminfo_db().cloned();
+            let sync = db.as_ref().is_some_and(|db| db.raw("Sync").is_some());
+            let color = termwiz::caps::Capabilities::new_with_hints(self.hints().terminfo_db(db))
+                .map_or(ColorLevel::Sixteen, |x| x.color_level());
+            self.probe = Some(Probe {
+                color,
+                sync,
+                render_caps,
+            });
+        }
+        Ok(self.probe.as_ref().expect("probed above"))
+    }
+
+    /// Capabilities for rendering, see [`ClientEnv::probe`]
+    pub fn render_caps(&mut self) -> io::Result<termwiz::caps::Capabilities> {
+        Ok(self.probe()?.render_caps.clone())
+    }
+
+    /// Whether desktop notifications should use OSC 777 instead of OSC 9
+    pub fn rxvt_notifications(&self) -> bool {
+        let term = self.get("TERM").unwrap_or_default();
+        term.contains("rxvt") || term.contains("foot") || self.get("VTE_VERSION").is_some()
+    }
+
+    /// Terminals known to understand hyperlinks and synchronized output
+    fn modern(&self) -> bool {
+        let term = self.get("TERM").unwrap_or_default();
+        MODERN_TERMS.iter().any(|x| term.contains(x))
+            || self.get("TERM_PROGRAM").is_some_and(|x| MODERN_PROGRAMS.contains(&x))
+            || self.get("WT_SESSION").is_some()
+    }
+
+    fn vte_version(&self) -> u32 {
+        self.get("VTE_VERSION")
+            .and_then(|x| x.parse::<u32>().ok())
+            .unwrap_or_default()
+    }
+
+    fn hyperlinks(&self) -> bool {
+        self.modern() || self.vte_version() >= 5000
+    }
+
+    pub fn capabilities(&mut self) -> Capabilities {
+        let (color, sync_terminfo) = match self.probe() {
+            Ok(probe) => (probe.color, probe.sync),
+            Err(_) => (ColorLevel::Sixteen, false),
+        };
+        let term = self.get("TERM").unwrap_or_default().to_string();
+        let program = self.get("TERM_PROGRAM");
+
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .into_iter()
+            .find_map(|x| self.get(x).filter(|x| !x.is_empty()));
+        let unicode = match locale {
+            Some(locale) => {
+                let locale = locale.to_ascii_lowercase();
+                locale.contains("utf-8") || locale.contains("utf8")
+            }
+            // Most clients do not send their locale, only assume the worst for old hardware terminals
+            None => !(term.is_empty() || term == "dumb" || term.starts_with("vt")),
+        };
+
+        let graphics = [Some(term.as_str()), program].into_iter().flatten();
+        let graphics = graphics.fold(Graphics::HalfBlocks, |graphics, name| {
+            if KITTY_GRAPHICS.iter().any(|x| name.contains(x)) {
+                Graphics::Kitty
+            } else if graphics == Graphics::HalfBlocks && SIXEL_GRAPHICS.iter().any(|x| name.contains(x)) {
+                Graphics::Sixel
+            } else {
+                graphics
+            }
+        });
+
+        Capabilities {
+            color,
+            graphics,
+            unicode,
+            hyperlinks: self.hyperlinks(),
+            synchronized_output: self.modern() || sync_terminfo || self.vte_version() >= 6800,
+            term,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Not in any terminfo database, so nothing depends on what the machine running the tests has
+    const UNKNOWN_TERM: &str = "sshdance-test";
+
+    fn env(vars: &[(&str, &str)]) -> ClientEnv {
+        let mut env = ClientEnv::default();
+        for (name, value) in vars {
+            env.set(name, value);
+        }
+        env
+    }
+
+    #[test]
+    fn locale_fallback_order() {
+        let unicode = |vars: &[(&str, &str)]| env(vars).capabilities().unicode;
+        assert!(!unicode(&[("TERM", UNKNOWN_TERM), ("LC_ALL", "C"), ("LANG", "en_US.UTF-8")]));
+        assert!(unicode(&[("TERM", UNKNOWN_TERM), ("LC_CTYPE", "de_DE.utf8"), ("LANG", "C")]));
+        // Empty ones are skipped
+        assert!(unicode(&[("TERM", UNKNOWN_TERM), ("LC_ALL", ""), ("LANG", "en_US.UTF-8")]));
+        assert!(!unicode(&[("TERM", UNKNOWN_TERM), ("LANG", "POSIX")]));
+    }
+
+    #[test]
+    fn unicode_without_locale_follows_term() {
+        assert!(env(&[("TERM", UNKNOWN_TERM)]).capabilities().unicode);
+        assert!(!env(&[("TERM", "vt100")]).capabilities().unicode);
+        assert!(!env(&[("TERM", "dumb")]).capabilities().unicode);
+        assert!(!env(&[]).capabilities().unicode);
+    }
+
+    #[test]
+    fn no_color() {
+        let color = |vars: &[(&str, &str)]| env(vars).capabilities().color;
+        let truecolor = [("TERM", UNKNOWN_TERM), ("COLORTERM", "truecolor")];
+        assert_eq!(color(&truecolor), ColorLevel::TrueColor);
+        assert_eq!(color(&[truecolor[0], truecolor[1], ("NO_COLOR", "1")]), ColorLevel::MonoChrome);
+        // Set but empty does not count
+        assert_eq!(color(&[truecolor[0], truecolor[1], ("NO_COLOR", "")]), ColorLevel::TrueColor);
+    }
+
+    #[test]
+    fn vte_version_thresholds() {
+        let caps = |version: &str| env(&[("TERM", UNKNOWN_TERM), ("VTE_VERSION", version)]).capabilities();
+
+        let caps_4999 = caps("4999");
+        assert!(!caps_4999.hyperlinks && !caps_4999.synchronized_output);
+        let caps_5000 = caps("5000");
+        assert!(caps_5000.hyperlinks && !caps_5000.synchronized_output);
+        let caps_6800 = caps("6800");
+        assert!(caps_6800.hyperlinks && caps_6800.synchronized_output);
+        assert!(!caps("garbage").hyperlinks);
+    }
+
+    #[test]
+    fn kitty_graphics_win_over_sixels() {
+        let graphics = |vars: &[(&str, &str)]| env(vars).capabilities().graphics;
+        assert_eq!(graphics(&[("TERM", UNKNOWN_TERM)]), Graphics::HalfBlocks);
+        assert_eq!(graphics(&[("TERM", "foot")]), Graphics::Sixel);
+        assert_eq!(graphics(&[("TERM", "xterm-kitty")]), Graphics::Kitty);
+        // Either way around
+        assert_eq!(graphics(&[("TERM", "foot"), ("TERM_PROGRAM", "WezTerm")]), Graphics::Kitty);
+        assert_eq!(graphics(&[("TERM", "xterm-kitty"), ("TERM_PROGRAM", "iTerm.app")]), Graphics::Kitty);
+    }
+
+    #[test]
+    fn probe_is_dropped_only_when_a_probed_variable_changes() {
+        let mut env = env(&[("TERM", UNKNOWN_TERM), ("COLORTERM", "truecolor")]);
+        env.capabilities();
+        assert!(env.probe.is_some());
+
+        env.set("LANG", "en_US.UTF-8");
+        env.set("TERM", UNKNOWN_TERM);
+        env.set("SHELL", "/bin/sh");
+        assert!(env.probe.is_some());
+
+        env.set("NO_COLOR", "1");
+        assert!(env.probe.is_none());
+        assert_eq!(env.capabilities().color, ColorLevel::MonoChrome);
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt::Write;
+
+use termwiz::{
+    escape::{
+        csi::{
+            Cursor, CursorStyle, DecPrivateMode, DecPrivateModeCode, Device, Keyboard,
+            KittyKeyboardFlags, Mode, CSI,
+        },
+        osc::{OperatingSystemCommand, Selection},
+    },
+    surface::CursorShape,
+};
+
+use crate::api::term::MouseCapture;
+
+/// Appends the sequence that turns a DEC private mode on or off
+pub fn dec_mode(out: &mut String, code: DecPrivateModeCode, enable: bool) {
+    let mode = DecPrivateMode::Code(code);
+    let mode = if enable {
+        Mode::SetDecPrivateMode(mode)
+    } else {
+        Mode::ResetDecPrivateMode(mode)
+    };
+    let _ = write!(out, "{}", CSI::Mode(mode));
+}
+
+pub fn mouse_capture(out: &mut String, capture: MouseCapture) {
+    // Start from a clean slate, terminals report using the highest mode that is still set
+    for code in [
+        DecPrivateModeCode::AnyEventMouse,
+        DecPrivateModeCode::ButtonEventMouse,
+        DecPrivateModeCode::MouseTracking,
+    ] {
+        dec_mode(out, code, false);
+    }
+
+    let code = match capture {
+        MouseCapture::Off => {
+            dec_mode(out, DecPrivateModeCode::SGRMouse, false);
+            return;
+        }
+        MouseCapture::Clicks => DecPrivateModeCode::MouseTracking,
+        MouseCapture::Drag => DecPrivateModeCode::ButtonEventMouse,
+        MouseCapture::Motion => DecPrivateModeCode::AnyEventMouse,
+    };
+    dec_mode(out, code, true);
+    // The input parser only understands the extended SGR encoding
+    dec_mode(out, DecPrivateModeCode::SGRMouse, true);
+}
+
+/// Pushes `flags` on the kitty keyboard stack and asks the client which flags are active.
+/// Every terminal answers the device attributes request that follows, so getting that answer
+/// without a keyboard report before it means the protocol is not supported
+pub fn push_keyboard_flags(out: &mut String, flags: KittyKeyboardFlags) {
+    let _ = write!(
+        out,
+        "\x1b[>{}u{}{}",
+        flags.bits(),
+        CSI::Keyboard(Keyboard::QueryKittySupport),
+        CSI::Device(Box::new(Device::RequestPrimaryDeviceAttributes))
+    );
+}
+
+/// Undoes [`push_keyboard_flags`], popping an empty stack just leaves the legacy encoding on
+pub fn pop_keyboard_flags(out: &mut String) {
+    let _ = write!(out, "{}", CSI::Keyboard(Keyboard::PopKittyState(1)));
+}
+
+/// Sets the cursor shape with DECSCUSR, [`CursorShape::Default`] brings back the client's own
+pub fn cursor_shape(out: &mut String, shape: CursorShape) {
+    let style = match shape {
+        CursorShape::Default => CursorStyle::Default,
+        CursorShape::BlinkingBlock => CursorStyle::BlinkingBlock,
+        CursorShape::SteadyBlock => CursorStyle::SteadyBlock,
+        CursorShape::BlinkingUnderline => CursorStyle::BlinkingUnderline,
+        CursorShape::SteadyUnderline => CursorStyle::SteadyUnderline,
+        CursorShape::BlinkingBar => CursorStyle::BlinkingBar,
+        CursorShape::SteadyBar => CursorStyle::SteadyBar,
+    };
+    let _ = write!(out, "{}", CSI::Cursor(Cursor::CursorStyle(style)));
+}
+
+/// Sets the window and tab title
+pub fn title(out: &mut String, title: &str) {
+    let osc = OperatingSystemCommand::SetIconNameAndWindowTitle(printable(title));
+    let _ = write!(out, "{osc}");
+}
+
+/// Puts `text` on the client clipboard with OSC 52, some clients ask the user first or ignore it
+pub fn clipboard(out: &mut String, text: &str) {
+    let osc = OperatingSystemCommand::SetSelection(Selection::CLIPBOARD, text.to_string());
+    let _ = write!(out, "{osc}");
+}
+
+/// Desktop notification, OSC 777 for rxvt and VTE descendants and OSC 9 for everyone else
+pub fn notify(out: &mut String, title: &str, body: &str, rxvt: bool) {
+    let osc = if rxvt {
+        OperatingSystemCommand::RxvtExtension(vec![
+            "notify".to_string(),
+            printable(title).replace(';', ","),
+            printable(body),
+        ])
+    } else if title.is_empty() {
+        OperatingSystemCommand::SystemNotification(printable(body))
+    } else {
+        OperatingSystemCommand::SystemNotification(printable(&format!("{title}: {body}")))
+    };
+    let _ = write!(out, "{osc}");
+}
+
+/// Drops control characters, which could end the sequence early and smuggle in their own
+fn printable(text: &str) -> String {
+    text.chars().filter(|x| !x.is_control()).collect()
+}
@@ -0,0 +1,318 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+};
+
+use ratatui::{
+    backend::WindowSize,
+    layout::{Rect, Size},
+};
+
+use crate::api::{
+    caps::{Capabilities, Graphics},
+    widgets::Image,
+};
+
+/// Cell size assumed when the client did not tell us its window size in pixels
+const DEFAULT_CELL: Size = Size {
+    width: 8,
+    height: 16,
+};
+
+/// Largest payload of a single kitty graphics escape, before base64
+const KITTY_CHUNK: usize = 3072;
+
+thread_local! {
+    // What the frame being rendered on this thread can use, set right before drawing it
+    static CONTEXT: Cell<GraphicsContext> = const {
+        Cell::new(GraphicsContext {
+            graphics: Graphics::HalfBlocks,
+            cell: DEFAULT_CELL,
+            unicode: true,
+        })
+    };
+    // Images placed by widgets while rendering the frame this thread is drawing
+    static PLACEMENTS: RefCell<Vec<Placement>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GraphicsContext {
+    pub graphics: Graphics,
+    /// Size of a single cell in pixels
+    pub cell: Size,
+    pub unicode: bool,
+}
+
+impl GraphicsContext {
+    pub fn new(capabilities: &Capabilities, window: WindowSize, streaming: bool) -> Self {
+        Self {
+            // Pixels cannot be printed as lines
+            graphics: if streaming {
+                Graphics::HalfBlocks
+            } else {
+                capabilities.graphics
+            },
+            cell: cell_size(window).unwrap_or(DEFAULT_CELL),
+            unicode: capabilities.unicode,
+        }
+    }
+}
+
+/// Size of a cell in pixels if the client reported its window size in pixels
+pub fn cell_size(window: WindowSize) -> Option<Size> {
+    let cells = window.columns_rows;
+    let pixels = window.pixels;
+    // Plenty of clients send 0, or something smaller than a pixel per cell
+    if cells.width == 0
+        || cells.height == 0
+        || pixels.width < cells.width
+        || pixels.height < cells.height
+    {
+        return None;
+    }
+
+    Some(Size {
+        width: pixels.width / cells.width,
+        height: pixels.height / cells.height,
+    })
+}
+
+/// Image drawn over the cells of `area` once the frame went out, see [`place`]
+#[derive(Clone)]
+pub struct Placement {
+    pub area: Rect,
+    /// Size the image is scaled to
+    pub pixels: Size,
+    pub graphics: Graphics,
+    pub image: Image,
+}
+
+impl PartialEq for Placement {
+    fn eq(&self, other: &Self) -> bool {
+        self.area == other.area
+            && self.pixels == other.pixels
+            && self.graphics == other.graphics
+            && self.image.id() == other.image.id()
+    }
+}
+
+/// Starts a new frame, images placed since the last one never reached a backend and are dropped
+pub fn set_context(context: GraphicsContext) {
+    CONTEXT.set(context);
+    PLACEMENTS.take();
+}
+
+pub fn context() -> GraphicsContext {
+    CONTEXT.get()
+}
+
+/// Queues an image for the frame being rendered, the backend draws it after the cells
+pub fn place(placement: Placement) {
+    PLACEMENTS.with_borrow_mut(|placements| placements.push(placement));
+}
+
+pub fn take_placements() -> Vec<Placement> {
+    PLACEMENTS.take()
+}
+
+/// Transmits the image as `id` and shows it at the cursor without moving it
+pub fn kitty(out: &mut String, placement: &Placement, id: u32) {
+    let pixels = placement.image.resample(placement.pixels);
+    let mut chunks = pixels.chunks(KITTY_CHUNK).peekable();
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let more = chunks.peek().is_some() as u8;
+        if first {
+            let _ = write!(
+                out,
+                "\x1b_Ga=T,f=32,s={},v={},i={id},c={},r={},C=1,q=2,m={more};",
+                placement.pixels.width,
+                placement.pixels.height,
+                placement.area.width,
+                placement.area.height,
+            );
+            first = false;
+        } else {
+            let _ = write!(out, "\x1b_Gm={more};");
+        }
+        base64(out, chunk);
+        out.push_str("\x1b\\");
+    }
+}
+
+/// Takes down an image shown with [`kitty`] and frees its data
+pub fn kitty_delete(out: &mut String, id: u32) {
+    let _ = write!(out, "\x1b_Ga=d,d=I,i={id},q=2\x1b\\");
+}
+
+/// Draws the image at the cursor, colors are reduced to the 6x6x6 cube of the 256 color palette
+pub fn sixel(out: &mut String, placement: &Placement) {
+    let Size { width, height } = placement.pixels;
+    let (width, height) = (width as usize, height as usize);
+    let pixels = placement.image.resample(placement.pixels);
+
+    // Palette index of every pixel, `None` where it is see through
+    let indices: Vec<Option<u8>> = pixels
+        .chunks_exact(4)
+        .map(|x| {
+            let level = |c: u8| (c as u16 * 5 + 127) / 255;
+            (x[3] >= 128).then(|| (36 * level(x[0]) + 6 * level(x[1]) + level(x[2])) as u8)
+        })
+        .collect();
+
+    // Pixel aspect ratio 1:1 and keep the background where pixels are transparent
+    let _ = write!(out, "\x1bP0;1;0q\"1;1;{width};{height}");
+    let mut used = [false; 216];
+    for index in indices.iter().flatten() {
+        used[*index as usize] = true;
+    }
+    for (index, _) in used.iter().enumerate().filter(|(_, used)| **used) {
+        let percent = |level: usize| level * 20;
+        let _ = write!(
+            out,
+            "#{index};2;{};{};{}",
+            percent(index / 36),
+            percent(index / 6 % 6),
+            percent(index % 6)
+        );
+    }
+
+    let mut band = vec![0u8; width];
+    for top in (0..height).step_by(6) {
+        if top > 0 {
+            out.push('-');
+        }
+        let rows = top..(top + 6).min(height);
+        let mut colors: Vec<u8> = rows
+            .clone()
+            .flat_map(|y| indices[y * width..(y + 1) * width].iter().flatten().copied())
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+
+        for color in colors {
+            band.fill(0);
+            for y in rows.clone() {
+                for (x, bits) in band.iter_mut().enumerate() {
+                    if indices[y * width + x] == Some(color) {
+                        *bits |= 1 << (y - top);
+                    }
+                }
+            }
+
+            let _ = write!(out, "#{color}");
+            let mut x = 0;
+            while x < width {
+                let run = band[x..].iter().take_while(|bits| **bits == band[x]).count();
+                let symbol = (band[x] + 63) as char;
+                if run > 3 {
+                    let _ = write!(out, "!{run}{symbol}");
+                } else {
+                    out.extend(std::iter::repeat_n(symbol, run));
+                }
+                x += run;
+            }
+            // Back to the start of the band for the next color
+            out.push('$');
+        }
+    }
+    out.push_str("\x1b\\");
+}
+
+fn base64(out: &mut String, data: &[u8]) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placement(width: u16, height: u16, pixels: Vec<u8>) -> Placement {
+        Placement {
+            area: Rect::new(0, 0, 1, 1),
+            pixels: Size::new(width, height),
+            graphics: Graphics::Kitty,
+            image: Image::from_rgba(width as u32, height as u32, pixels).unwrap(),
+        }
+    }
+
+    fn encode(data: &[u8]) -> String {
+        let mut out = String::new();
+        base64(&mut out, data);
+        out
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(encode(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+
+    #[test]
+    fn cell_size_needs_pixels() {
+        let window = |columns, rows, width, height| WindowSize {
+            columns_rows: Size::new(columns, rows),
+            pixels: Size::new(width, height),
+        };
+        assert_eq!(cell_size(window(80, 24, 800, 480)), Some(Size::new(10, 20)));
+        assert_eq!(cell_size(window(80, 24, 0, 0)), None);
+        assert_eq!(cell_size(window(0, 0, 800, 480)), None);
+    }
+
+    #[test]
+    fn kitty_is_sent_in_chunks() {
+        // 1024 pixels make 4096 bytes, more than fits into one escape
+        let placement = placement(32, 32, vec![7; 32 * 32 * 4]);
+        let mut out = String::new();
+        kitty(&mut out, &placement, 9);
+
+        let escapes: Vec<_> = out.split_terminator("\x1b\\").collect();
+        assert_eq!(escapes.len(), 2);
+        assert!(escapes[0].starts_with("\x1b_Ga=T,f=32,s=32,v=32,i=9,c=1,r=1,C=1,q=2,m=1;"));
+        assert!(escapes[1].starts_with("\x1b_Gm=0;"));
+        let payload = escapes[0].len() - escapes[0].find(';').unwrap() - 1;
+        assert_eq!(payload, KITTY_CHUNK / 3 * 4);
+    }
+
+    #[test]
+    fn sixel_bands_and_transparency() {
+        // A red pixel over a transparent one, then 6 more rows of green to start a second band
+        let mut pixels = vec![255, 0, 0, 255, 0, 0, 0, 0];
+        for _ in 0..6 {
+            pixels.extend_from_slice(&[0, 255, 0, 255]);
+        }
+        let placement = placement(1, 8, pixels);
+        let mut out = String::new();
+        sixel(&mut out, &placement);
+
+        // Red is cube entry 180 and green 30, each band holds six rows as bits from the top
+        assert_eq!(
+            out,
+            "\x1bP0;1;0q\"1;1;1;8#30;2;0;100;0#180;2;100;0;0#30{$#180@$-#30B$\x1b\\"
+        );
+    }
+
+    #[test]
+    fn sixel_run_length() {
+        let placement = placement(5, 1, [0, 0, 0, 255].repeat(5));
+        let mut out = String::new();
+        sixel(&mut out, &placement);
+        assert!(out.ends_with("#0!5@$\x1b\\"), "{out:?}");
+    }
+}
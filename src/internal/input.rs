@@ -0,0 +1,762 @@
+use std::sync::{Arc, Mutex};
+
+use termwiz::input::{InputEvent, InputParser, KeyCode, KeyEvent, Modifiers};
+
+use crate::{
+    api::{
+        caps::TerminalModes,
+        term::{KeyEventKind, KeyboardEvent},
+    },
+    internal::term::TerminalInputs,
+};
+
+const ESC: u8 = 0x1b;
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+/// Longest escape sequence held back while waiting for the rest of it
+const MAX_SEQUENCE: usize = 256;
+/// Longer pastes are reported in several parts so a paste that never ends cannot eat up memory
+const MAX_PASTE: usize = 64 * 1024;
+
+/// The SSH handler feeds the decoder while the session flushes it once the escape timeout runs out
+pub type SharedDecoder = Arc<Mutex<InputDecoder>>;
+
+/// Turns client bytes into terminal inputs, picking out the sequences [`InputParser`] does not know about
+pub struct InputDecoder {
+    parser: InputParser,
+    // Start of a sequence that was cut off at the end of the last packet
+    pending: Vec<u8>,
+    // Inside a bracketed paste nothing but the end marker means anything
+    pasting: bool,
+    // Bytes of the current paste the parser is holding on to
+    pasted: usize,
+    // Start of a UTF-8 char cut off at the end of the last packet, for clients that said they do not send UTF-8
+    partial: Vec<u8>,
+    modes: TerminalModes,
+}
+
+enum Sequence {
+    Complete(usize),
+    Incomplete,
+    Other,
+}
+
+impl InputDecoder {
+    pub fn new(modes: TerminalModes) -> Self {
+        Self {
+            parser: InputParser::new(),
+            pending: Vec::new(),
+            pasting: false,
+            pasted: 0,
+            partial: Vec::new(),
+            modes,
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Vec<TerminalInputs> {
+        let mut buf = std::mem::take(&mut self.pending);
+        if self.modes.utf8 == Some(false) {
+            let mut data = [std::mem::take(&mut self.partial).as_slice(), data].concat();
+            // Some clients send UTF-8 anyway, a char cut off at the end of the packet is not Latin-1 yet
+            self.partial = data.split_off(data.len() - partial_utf8(&data));
+            latin1(&data, &mut buf);
+        } else {
+            buf.extend_from_slice(data);
+        }
+
+        let mut out = self.scan(buf);
+        if !self.partial.is_empty() && !out.contains(&TerminalInputs::EscapePending) {
+            out.push(TerminalInputs::EscapePending);
+        }
+        out
+    }
+
+    /// Splits `buf`, which starts with whatever was pending, into inputs
+    fn scan(&mut self, buf: Vec<u8>) -> Vec<TerminalInputs> {
+        let mut out = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < buf.len() {
+            if self.pasting {
+                let marker = find(&buf[i..], PASTE_END).map(|pos| i + pos);
+                // The end marker might be split between two packets
+                let end = marker.unwrap_or(buf.len() - partial_suffix(&buf[i..], PASTE_END));
+
+                if end - i > MAX_PASTE - self.pasted {
+                    // Hand over what was pasted so far and go on with a new paste, without cutting a char in two
+                    let mut split = i + MAX_PASTE - self.pasted;
+                    while split > i && buf[split] & 0xc0 == 0x80 {
+                        split -= 1;
+                    }
+                    self.forward(&buf[start..split], &mut out);
+                    self.forward(PASTE_END, &mut out);
+                    self.forward(PASTE_START, &mut out);
+                    self.pasted = 0;
+                    start = split;
+                    i = split;
+                    continue;
+                }
+
+                let Some(marker) = marker else {
+                    self.pasted += end - i;
+                    self.forward(&buf[start..end], &mut out);
+                    self.pending = buf[end..].to_vec();
+                    return out;
+                };
+                i = marker + PASTE_END.len();
+                self.pasting = false;
+                self.pasted = 0;
+                continue;
+            }
+
+            if buf[i] != ESC {
+                // After ESC the byte belongs to an Alt chord, which the parser takes care of
+                let alt = i > 0 && buf[i - 1] == ESC;
+                if let Some(inputs) = control_byte(&self.modes, buf[i]).filter(|_| !alt) {
+                    self.forward(&buf[start..i], &mut out);
+                    out.extend(inputs);
+                    start = i + 1;
+                }
+                i += 1;
+                continue;
+            }
+
+            match sequence(&buf[i..]) {
+                Sequence::Incomplete => {
+                    self.forward(&buf[start..i], &mut out);
+                    self.pending = buf[i..].to_vec();
+                    out.push(TerminalInputs::EscapePending);
+                    return out;
+                }
+                Sequence::Other => i += 1,
+                Sequence::Complete(len) => {
+                    let seq = &buf[i..i + len];
+                    self.pasting = seq == PASTE_START;
+
+                    if let Some(input) = intercept(seq) {
+                        self.forward(&buf[start..i], &mut out);
+                        out.push(input);
+                        start = i + len;
+                    }
+                    i += len;
+                }
+            }
+        }
+
+        self.forward(&buf[start..], &mut out);
+        out
+    }
+
+    /// Whether the start of an escape sequence or a char is waiting for the rest of it
+    pub fn is_holding(&self) -> bool {
+        (!self.pasting && !self.pending.is_empty()) || !self.partial.is_empty()
+    }
+
+    /// Gives up on the rest of a cut off sequence, a lone ESC becomes the Escape key
+    pub fn flush(&mut self) -> Vec<TerminalInputs> {
+        let mut out = Vec::new();
+        let partial = std::mem::take(&mut self.partial);
+        if !partial.is_empty() {
+            // Nothing completed the char, so it was Latin-1 after all
+            let mut buf = std::mem::take(&mut self.pending);
+            latin1(&partial, &mut buf);
+            out = self.scan(buf);
+            out.retain(|x| *x != TerminalInputs::EscapePending);
+        }
+
+        // A cut off paste end marker is always completed by the client
+        if !self.pasting {
+            let pending = std::mem::take(&mut self.pending);
+            self.forward(&pending, &mut out);
+        }
+        out
+    }
+
+    fn forward(&mut self, data: &[u8], out: &mut Vec<TerminalInputs>) {
+        if data.is_empty() {
+            return;
+        }
+
+        // Cut off sequences are held back in `pending`, so whatever reaches the parser is complete
+        self.parser
+            .parse(data, |event| out.push(TerminalInputs::Input(event)), false);
+    }
+}
+
+/// What the client's terminal modes make of `byte`, `None` leaves it to [`InputParser`], which
+/// takes both DEL and ^H for Backspace and both CR and LF for Enter
+fn control_byte(modes: &TerminalModes, byte: u8) -> Option<Vec<TerminalInputs>> {
+    let key = |key, modifiers| {
+        Some(vec![TerminalInputs::Input(InputEvent::Key(KeyEvent {
+            key,
+            modifiers,
+        }))])
+    };
+
+    match byte {
+        b'\r' if modes.igncr => Some(Vec::new()),
+        b'\n' if modes.icrnl && !modes.inlcr => key(KeyCode::Char('j'), Modifiers::CTRL),
+        0x08 if modes.erase == Some(0x7f) => key(KeyCode::Char('h'), Modifiers::CTRL),
+        0x7f if modes.erase == Some(0x08) => key(KeyCode::Delete, Modifiers::NONE),
+        _ => None,
+    }
+}
+
+/// Copies `data` into `out`, reading whatever is not valid UTF-8 as Latin-1
+fn latin1(data: &[u8], out: &mut Vec<u8>) {
+    for chunk in data.utf8_chunks() {
+        out.extend_from_slice(chunk.valid().as_bytes());
+        for byte in chunk.invalid() {
+            let mut encoded = [0; 2];
+            out.extend_from_slice(char::from(*byte).encode_utf8(&mut encoded).as_bytes());
+        }
+    }
+}
+
+/// Length of the UTF-8 char cut off at the end of `data`, if any
+fn partial_utf8(data: &[u8]) -> usize {
+    for len in 1..=data.len().min(3) {
+        let byte = data[data.len() - len];
+        if byte & 0xc0 == 0x80 {
+            continue;
+        }
+        let needed = match byte {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return 0,
+        };
+        return if len < needed { len } else { 0 };
+    }
+    0
+}
+
+/// Length of the escape sequence at the start of `buf`, which begins with ESC
+fn sequence(buf: &[u8]) -> Sequence {
+    match buf.get(1) {
+        None => Sequence::Incomplete,
+        Some(b'[') => {
+            for (i, byte) in buf.iter().enumerate().take(MAX_SEQUENCE).skip(2) {
+                match byte {
+                    0x20..=0x3f => continue,
+                    0x40..=0x7e => return Sequence::Complete(i + 1),
+                    _ => return Sequence::Other,
+                }
+            }
+            // Nothing we know of is this long, leave it to the parser instead of holding on to it
+            if buf.len() >= MAX_SEQUENCE {
+                Sequence::Other
+            } else {
+                Sequence::Incomplete
+            }
+        }
+        Some(b'O') if buf.len() == 2 => Sequence::Incomplete,
+        Some(_) => Sequence::Other,
+    }
+}
+
+/// Handles the complete CSI sequence `seq` if it is one [`InputParser`] gets wrong
+fn intercept(seq: &[u8]) -> Option<TerminalInputs> {
+    let (&final_byte, params) = seq[2..].split_last()?;
+    match (params.first().copied(), final_byte) {
+        (None, b'I') => Some(TerminalInputs::Focus(true)),
+        (None, b'O') => Some(TerminalInputs::Focus(false)),
+        (Some(b'?'), b'u') => Some(TerminalInputs::KeyboardSupport),
+        (Some(b'?'), b'c') => Some(TerminalInputs::DeviceAttributes),
+        _ => kitty_key(params, final_byte).map(TerminalInputs::Key),
+    }
+}
+
+/// Decodes a key report of the kitty keyboard protocol, `CSI code[:shifted] ; modifiers[:event] u`.
+/// Functional keys keep their legacy `~` and letter forms, those are only ours when they carry an event type
+fn kitty_key(params: &[u8], final_byte: u8) -> Option<KeyboardEvent> {
+    // Fields may be left out, but what is there has to be a number
+    if !params.iter().all(|x| x.is_ascii_digit() || matches!(x, b';' | b':')) {
+        return None;
+    }
+    let params = std::str::from_utf8(params).ok()?;
+    let mut fields = params.split(';').map(|field| {
+        field
+            .split(':')
+            .map(|x| x.parse::<u32>().ok())
+            .collect::<Vec<_>>()
+    });
+    let key_field = fields.next().unwrap_or_default();
+    let modifier_field = fields.next().unwrap_or_default();
+
+    let code = key_field.first().copied().flatten().unwrap_or(1);
+    let shifted = key_field.get(1).copied().flatten();
+    let bits = modifier_field
+        .first()
+        .copied()
+        .flatten()
+        .unwrap_or(1)
+        .saturating_sub(1);
+    let event = modifier_field.get(1).copied().flatten();
+
+    let key = match final_byte {
+        b'u' => kitty_keycode(code)?,
+        _ if event.is_none() => return None,
+        b'~' => match code {
+            2 => KeyCode::Insert,
+            3 => KeyCode::Delete,
+            5 => KeyCode::PageUp,
+            6 => KeyCode::PageDown,
+            7 => KeyCode::Home,
+            8 => KeyCode::End,
+            11..=15 => KeyCode::Function((code - 10) as u8),
+            17..=21 => KeyCode::Function((code - 11) as u8),
+            23..=24 => KeyCode::Function((code - 12) as u8),
+            57427 => KeyCode::KeyPadBegin,
+            _ => return None,
+        },
+        b'A' => KeyCode::UpArrow,
+        b'B' => KeyCode::DownArrow,
+        b'C' => KeyCode::RightArrow,
+        b'D' => KeyCode::LeftArrow,
+        b'E' => KeyCode::KeyPadBegin,
+        b'F' => KeyCode::End,
+        b'H' => KeyCode::Home,
+        b'P' => KeyCode::Function(1),
+        b'Q' => KeyCode::Function(2),
+        b'S' => KeyCode::Function(4),
+        _ => return None,
+    };
+
+    let mut modifiers = Modifiers::NONE;
+    for (bit, modifier) in [
+        (1, Modifiers::SHIFT),
+        (2, Modifiers::ALT),
+        (4, Modifiers::CTRL),
+        (8, Modifiers::SUPER),
+    ] {
+        if bits & bit != 0 {
+            modifiers |= modifier;
+        }
+    }
+
+    // Report shifted letters the way the legacy encoding does, as the upper case char without SHIFT
+    let key = match (key, shifted.and_then(char::from_u32)) {
+        (KeyCode::Char(_), Some(shifted)) if modifiers.contains(Modifiers::SHIFT) => {
+            modifiers -= Modifiers::SHIFT;
+            KeyCode::Char(shifted)
+        }
+        (key, _) => key,
+    };
+
+    let kind = match event {
+        Some(2) => KeyEventKind::Repeat,
+        Some(3) => KeyEventKind::Release,
+        _ => KeyEventKind::Press,
+    };
+
+    Some(KeyboardEvent {
+        key,
+        modifiers,
+        kind,
+    })
+}
+
+/// Maps the key numbers of the kitty keyboard protocol, which are unicode code points
+/// except for a block of the private use area
+fn kitty_keycode(code: u32) -> Option<KeyCode> {
+    const NUMPAD: [KeyCode; 10] = [
+        KeyCode::Numpad0,
+        KeyCode::Numpad1,
+        KeyCode::Numpad2,
+        KeyCode::Numpad3,
+        KeyCode::Numpad4,
+        KeyCode::Numpad5,
+        KeyCode::Numpad6,
+        KeyCode::Numpad7,
+        KeyCode::Numpad8,
+        KeyCode::Numpad9,
+    ];
+
+    let key = match code {
+        9 => KeyCode::Tab,
+        13 => KeyCode::Enter,
+        27 => KeyCode::Escape,
+        127 => KeyCode::Backspace,
+        57358 => KeyCode::CapsLock,
+        57359 => KeyCode::ScrollLock,
+        57360 => KeyCode::NumLock,
+        57361 => KeyCode::PrintScreen,
+        57362 => KeyCode::Pause,
+        57363 => KeyCode::Menu,
+        57376..=57398 => KeyCode::Function((code - 57376 + 13) as u8),
+        57399..=57408 => NUMPAD[(code - 57399) as usize],
+        57409 => KeyCode::Decimal,
+        57410 => KeyCode::Divide,
+        57411 => KeyCode::Multiply,
+        57412 => KeyCode::Subtract,
+        57413 => KeyCode::Add,
+        57414 => KeyCode::Enter,
+        57416 => KeyCode::Separator,
+        57417 => KeyCode::LeftArrow,
+        57418 => KeyCode::RightArrow,
+        57419 => KeyCode::UpArrow,
+        57420 => KeyCode::DownArrow,
+        57421 => KeyCode::KeyPadPageUp,
+        57422 => KeyCode::KeyPadPageDown,
+        57423 => KeyCode::KeyPadHome,
+        57424 => KeyCode::KeyPadEnd,
+        57425 => KeyCode::Insert,
+        57426 => KeyCode::Delete,
+        57427 => KeyCode::KeyPadBegin,
+        57428 | 57430 => KeyCode::MediaPlayPause,
+        57432 => KeyCode::MediaStop,
+        57435 => KeyCode::MediaNextTrack,
+        57436 => KeyCode::MediaPrevTrack,
+        57438 => KeyCode::VolumeDown,
+        57439 => KeyCode::VolumeUp,
+        57440 => KeyCode::VolumeMute,
+        57441 => KeyCode::LeftShift,
+        57442 => KeyCode::LeftControl,
+        57443 => KeyCode::LeftAlt,
+        57444 => KeyCode::LeftWindows,
+        57447 => KeyCode::RightShift,
+        57448 => KeyCode::RightControl,
+        57449 => KeyCode::RightAlt,
+        57450 => KeyCode::RightWindows,
+        57445 | 57451 => KeyCode::Hyper,
+        57446 | 57452 => KeyCode::Meta,
+        // The rest of the private use area is keys we have no name for
+        57344..=63743 => return None,
+        code => KeyCode::Char(char::from_u32(code)?),
+    };
+    Some(key)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
+
+/// Length of the longest end of `buf` that `marker` starts with
+fn partial_suffix(buf: &[u8], marker: &[u8]) -> usize {
+    (1..marker.len().min(buf.len() + 1))
+        .rev()
+        .find(|len| buf.ends_with(&marker[..*len]))
+        .unwrap_or(0)
+}
+
+/// Squashes runs of plain typed text into paste events so a flood takes up a handful of queue slots
+pub fn coalesce(events: impl IntoIterator<Item = TerminalInputs>) -> Vec<TerminalInputs> {
+    let mut out = Vec::new();
+    let mut text = String::new();
+
+    for event in events {
+        match event {
+            TerminalInputs::Input(InputEvent::Key(KeyEvent {
+                key: KeyCode::Char(c),
+                modifiers,
+            })) if (modifiers - Modifiers::SHIFT).is_empty() => text.push(c),
+            TerminalInputs::Input(InputEvent::Key(KeyEvent {
+                key: KeyCode::Enter,
+                modifiers: Modifiers::NONE,
+            })) => text.push('\n'),
+            TerminalInputs::Input(InputEvent::Paste(paste)) => text.push_str(&paste),
+            event => {
+                if !text.is_empty() {
+                    out.push(TerminalInputs::Input(InputEvent::Paste(std::mem::take(
+                        &mut text,
+                    ))));
+                }
+                out.push(event);
+            }
+        }
+    }
+
+    if !text.is_empty() {
+        out.push(TerminalInputs::Input(InputEvent::Paste(text)));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(packets: &[&[u8]]) -> Vec<TerminalInputs> {
+        let mut decoder = InputDecoder::new(TerminalModes::default());
+        packets.iter().flat_map(|x| decoder.decode(x)).collect()
+    }
+
+    fn paste(text: &str) -> TerminalInputs {
+        TerminalInputs::Input(InputEvent::Paste(text.to_string()))
+    }
+
+    #[test]
+    fn paste_is_reported_whole() {
+        assert_eq!(decode(&[b"\x1b[200~a\x1b[Ab\x1b[201~"]), [paste("a\x1b[Ab")]);
+    }
+
+    #[test]
+    fn paste_end_split_between_packets() {
+        assert_eq!(decode(&[b"\x1b[200~hello\x1b[2", b"01~"]), [paste("hello")]);
+    }
+
+    #[test]
+    fn long_paste_is_reported_in_parts() {
+        let text = "ab".repeat(MAX_PASTE);
+        let mut data = PASTE_START.to_vec();
+        data.extend_from_slice(text.as_bytes());
+        data.extend_from_slice(PASTE_END);
+
+        let events = decode(&data.chunks(1000).collect::<Vec<_>>());
+        assert_eq!(events.len(), 2);
+        let mut joined = String::new();
+        for event in events {
+            let TerminalInputs::Input(InputEvent::Paste(part)) = event else {
+                panic!("expected a paste, got {event:?}");
+            };
+            assert!(part.len() <= MAX_PASTE);
+            joined.push_str(&part);
+        }
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn unterminated_paste_stays_bounded() {
+        let mut decoder = InputDecoder::new(TerminalModes::default());
+        let mut events = decoder.decode(PASTE_START);
+        for _ in 0..100 {
+            events.extend(decoder.decode(&[b'x'; 4096]));
+        }
+        assert_eq!(events.len(), 100 * 4096 / MAX_PASTE);
+        assert!(decoder.pasted < MAX_PASTE);
+    }
+
+    #[test]
+    fn paste_parts_keep_chars_whole() {
+        let text = "é".repeat(MAX_PASTE);
+        let mut data = PASTE_START.to_vec();
+        data.extend_from_slice(text.as_bytes());
+        data.extend_from_slice(PASTE_END);
+
+        let joined: String = decode(&[&data])
+            .into_iter()
+            .map(|event| match event {
+                TerminalInputs::Input(InputEvent::Paste(part)) => part,
+                event => panic!("expected a paste, got {event:?}"),
+            })
+            .collect();
+        assert_eq!(joined, text);
+    }
+
+    fn key(key: KeyCode, modifiers: Modifiers, kind: KeyEventKind) -> TerminalInputs {
+        TerminalInputs::Key(KeyboardEvent {
+            key,
+            modifiers,
+            kind,
+        })
+    }
+
+    #[test]
+    fn kitty_plain_key() {
+        assert_eq!(
+            decode(&[b"\x1b[97u"]),
+            [key(KeyCode::Char('a'), Modifiers::NONE, KeyEventKind::Press)]
+        );
+    }
+
+    #[test]
+    fn kitty_modifiers_and_event_types() {
+        assert_eq!(
+            decode(&[b"\x1b[97;5:2u\x1b[97;7:3u"]),
+            [
+                key(KeyCode::Char('a'), Modifiers::CTRL, KeyEventKind::Repeat),
+                key(KeyCode::Char('a'), Modifiers::CTRL | Modifiers::ALT, KeyEventKind::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn kitty_shifted_letter_is_upper_case() {
+        assert_eq!(
+            decode(&[b"\x1b[97:65;2u"]),
+            [key(KeyCode::Char('A'), Modifiers::NONE, KeyEventKind::Press)]
+        );
+        assert_eq!(
+            decode(&[b"\x1b[97:65;6u"]),
+            [key(KeyCode::Char('A'), Modifiers::CTRL, KeyEventKind::Press)]
+        );
+    }
+
+    #[test]
+    fn kitty_functional_keys() {
+        assert_eq!(
+            decode(&[b"\x1b[1;1:3A\x1b[15;3:1~\x1b[57399u\x1b[57376u"]),
+            [
+                key(KeyCode::UpArrow, Modifiers::NONE, KeyEventKind::Release),
+                key(KeyCode::Function(5), Modifiers::ALT, KeyEventKind::Press),
+                key(KeyCode::Numpad0, Modifiers::NONE, KeyEventKind::Press),
+                key(KeyCode::Function(13), Modifiers::NONE, KeyEventKind::Press),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_keys_are_left_to_the_parser() {
+        // Without an event type these are the legacy encodings termwiz already knows
+        assert_eq!(kitty_key(b"1;5", b'A'), None);
+        assert_eq!(kitty_key(b"3", b'~'), None);
+        assert_eq!(
+            decode(&[b"\x1b[A"]),
+            [TerminalInputs::Input(InputEvent::Key(KeyEvent {
+                key: KeyCode::UpArrow,
+                modifiers: Modifiers::NONE,
+            }))]
+        );
+    }
+
+    #[test]
+    fn kitty_unknown_private_use_keys_are_ignored() {
+        assert_eq!(kitty_key(b"57500", b'u'), None);
+        assert_eq!(kitty_key(b"garbage", b'u'), None);
+    }
+
+    #[test]
+    fn keyboard_and_device_replies() {
+        assert_eq!(
+            decode(&[b"\x1b[?1u\x1b[?62;22c\x1b[I\x1b[O"]),
+            [
+                TerminalInputs::KeyboardSupport,
+                TerminalInputs::DeviceAttributes,
+                TerminalInputs::Focus(true),
+                TerminalInputs::Focus(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn sequence_split_between_packets() {
+        assert_eq!(
+            decode(&[b"\x1b[9", b"7;5u"]),
+            [
+                TerminalInputs::EscapePending,
+                key(KeyCode::Char('a'), Modifiers::CTRL, KeyEventKind::Press),
+            ]
+        );
+        assert_eq!(
+            decode(&[b"\x1b", b"[A"]),
+            [
+                TerminalInputs::EscapePending,
+                TerminalInputs::Input(InputEvent::Key(KeyEvent {
+                    key: KeyCode::UpArrow,
+                    modifiers: Modifiers::NONE,
+                })),
+            ]
+        );
+    }
+
+    #[test]
+    fn lone_escape_waits_for_flush() {
+        let mut decoder = InputDecoder::new(TerminalModes::default());
+        assert_eq!(decoder.decode(b"\x1b"), [TerminalInputs::EscapePending]);
+        assert!(decoder.is_holding());
+        assert_eq!(
+            decoder.flush(),
+            [TerminalInputs::Input(InputEvent::Key(KeyEvent {
+                key: KeyCode::Escape,
+                modifiers: Modifiers::NONE,
+            }))]
+        );
+        assert!(!decoder.is_holding());
+    }
+
+    #[test]
+    fn unterminated_sequence_stays_bounded() {
+        let mut decoder = InputDecoder::new(TerminalModes::default());
+        decoder.decode(b"\x1b[");
+        for _ in 0..1000 {
+            decoder.decode(b"1;1;1;1;");
+            assert!(decoder.pending.len() < MAX_SEQUENCE);
+        }
+        assert!(!decoder.is_holding());
+    }
+
+    fn typed(c: char, modifiers: Modifiers) -> TerminalInputs {
+        let key = match c {
+            '\r' => KeyCode::Enter,
+            c => KeyCode::Char(c),
+        };
+        TerminalInputs::Input(InputEvent::Key(KeyEvent { key, modifiers }))
+    }
+
+    fn keys(text: &str) -> Vec<TerminalInputs> {
+        text.chars().map(|c| typed(c, Modifiers::NONE)).collect()
+    }
+
+    #[test]
+    fn terminal_modes_pick_what_control_bytes_mean() {
+        let modes = TerminalModes {
+            erase: Some(0x7f),
+            icrnl: true,
+            ..Default::default()
+        };
+        let mut decoder = InputDecoder::new(modes);
+        assert_eq!(
+            decoder.decode(b"\n\x08"),
+            [typed('j', Modifiers::CTRL), typed('h', Modifiers::CTRL)]
+        );
+
+        let modes = TerminalModes {
+            erase: Some(0x08),
+            igncr: true,
+            ..Default::default()
+        };
+        let mut decoder = InputDecoder::new(modes);
+        assert_eq!(
+            decoder.decode(b"a\r\x7f"),
+            [
+                typed('a', Modifiers::NONE),
+                TerminalInputs::Input(InputEvent::Key(KeyEvent {
+                    key: KeyCode::Delete,
+                    modifiers: Modifiers::NONE,
+                })),
+            ]
+        );
+    }
+
+    #[test]
+    fn latin1_without_iutf8() {
+        let modes = TerminalModes {
+            utf8: Some(false),
+            ..Default::default()
+        };
+        let mut decoder = InputDecoder::new(modes);
+        assert_eq!(decoder.decode(b"\xe9t\xe9!"), keys("été!"));
+    }
+
+    #[test]
+    fn utf8_split_between_packets_without_iutf8() {
+        let modes = TerminalModes {
+            utf8: Some(false),
+            ..Default::default()
+        };
+        let mut decoder = InputDecoder::new(modes);
+        assert_eq!(decoder.decode(b"a\xc3"), [typed('a', Modifiers::NONE), TerminalInputs::EscapePending]);
+        assert!(decoder.is_holding());
+        assert_eq!(decoder.decode(b"\xa9"), [typed('é', Modifiers::NONE)]);
+        assert!(!decoder.is_holding());
+
+        // Nothing came to complete it, so it was Latin-1
+        assert_eq!(decoder.decode(b"\xc3"), [TerminalInputs::EscapePending]);
+        assert_eq!(decoder.flush(), [typed('Ã', Modifiers::NONE)]);
+        assert!(!decoder.is_holding());
+    }
+
+    #[test]
+    fn partial_utf8_lengths() {
+        assert_eq!(partial_utf8(b""), 0);
+        assert_eq!(partial_utf8(b"abc"), 0);
+        assert_eq!(partial_utf8("é".as_bytes()), 0);
+        assert_eq!(partial_utf8(b"a\xc3"), 1);
+        assert_eq!(partial_utf8(b"\xe2\x82"), 2);
+        assert_eq!(partial_utf8(b"\xf0\x9f\x98"), 3);
+        assert_eq!(partial_utf8(b"\xe9"), 1);
+        assert_eq!(partial_utf8(b"\xff"), 0);
+    }
+}
@@ -1,69 +1,331 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    num::NonZero,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
 
-use russh::{server::Handler, ChannelId};
-use termwiz::input::InputParser;
-use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
-use tracing::trace;
+use ratatui::{backend::WindowSize, layout::Size};
+use russh::{
+    server::{Handler, Msg},
+    ChannelId, ChannelWriteHalf, Disconnect,
+};
+use termwiz::input::{KeyCode, Modifiers};
+use tokio::{
+    sync::mpsc::{error::TrySendError, Sender},
+    task::JoinHandle,
+};
+use tracing::{trace, warn};
 
 use crate::{
-    api::{ClientHandler, Decision},
-    internal::term::TerminalInputs,
+    api::{caps::TerminalModes, keymap::KeyChord, ClientHandler, Decision, InputOverflow},
+    audit::{AuditEvent, AuditEventKind, AuditSink},
+    internal::{env::ClientEnv, input::SharedDecoder, term::TerminalInputs},
+    registry::{AuthMethod, SessionMetrics, SessionRegistry},
 };
 
+pub(crate) mod backend;
+mod env;
+mod escape;
+pub(crate) mod graphics;
+mod input;
+mod palette;
 mod sync_sink;
 mod term;
 
+/// Per session settings picked in [`crate::SshDanceBuilder`]
+pub(crate) struct SessionConfig {
+    pub input_queue: usize,
+    pub input_overflow: InputOverflow,
+    pub panic_message: String,
+    pub quit_binding: Option<KeyChord>,
+    pub quit_message: String,
+    pub max_fps: Option<NonZero<u32>>,
+    pub escape_timeout: Duration,
+    pub registry: SessionRegistry,
+    pub audit: Option<Arc<dyn AuditSink>>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            input_queue: 256,
+            input_overflow: InputOverflow::default(),
+            panic_message: "Something went wrong on our side, sorry about that".to_string(),
+            quit_binding: Some(KeyChord::new(KeyCode::Char('d'), Modifiers::CTRL)),
+            quit_message: "See you next time\nSmelly furries".to_string(),
+            max_fps: NonZero::new(60),
+            escape_timeout: Duration::from_millis(50),
+            registry: SessionRegistry::default(),
+            audit: None,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn audit(&self, connection: u64, addr: Option<SocketAddr>, kind: AuditEventKind) {
+        if let Some(sink) = &self.audit {
+            sink.record(&AuditEvent {
+                time: SystemTime::now(),
+                connection,
+                addr,
+                kind,
+            });
+        }
+    }
+}
+
+/// The client handler is shared with the terminal tasks so they can report back to it
+type SharedHandler<T> = Arc<Mutex<T>>;
+
+/// Locks the client handler, a panic inside one of its callbacks should not take the session down
+fn lock<T>(handler: &Mutex<T>) -> MutexGuard<'_, T> {
+    handler.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Window size from a pty or window change request. Pixel sizes that do not fit count as not
+/// reported, a clamped one would give a wrong cell size
+fn window_size(col_width: u32, row_height: u32, pix_width: u32, pix_height: u32) -> WindowSize {
+    let pixels = match (u16::try_from(pix_width), u16::try_from(pix_height)) {
+        (Ok(width), Ok(height)) => Size::new(width, height),
+        _ => Size::new(0, 0),
+    };
+    WindowSize {
+        columns_rows: Size::new(col_width as u16, row_height as u16),
+        pixels,
+    }
+}
+
 pub struct SshSessionHandler<T: ClientHandler> {
-    handler: T,
+    handler: SharedHandler<T>,
+    config: Arc<SessionConfig>,
+    connection: u64,
+    connected: Instant,
+    addr: Option<SocketAddr>,
+    user: Option<String>,
     channels: HashMap<ChannelId, ChannelState>,
 }
 
 impl<T: ClientHandler> SshSessionHandler<T> {
-    pub fn create(addr: Option<std::net::SocketAddr>) -> Self {
+    pub(crate) fn create(
+        addr: Option<SocketAddr>,
+        connection: u64,
+        config: Arc<SessionConfig>,
+    ) -> Self {
+        config.audit(connection, addr, AuditEventKind::ConnectionOpened);
         SshSessionHandler {
-            handler: T::create(addr),
+            handler: Arc::new(Mutex::new(T::create(addr))),
+            config,
+            connection,
+            connected: Instant::now(),
+            addr,
+            user: None,
             channels: HashMap::new(),
         }
     }
+
+    fn audit(&self, kind: AuditEventKind) {
+        self.config.audit(self.connection, self.addr, kind);
+    }
+
+    fn auth_failed(&self, user: &str, method: AuthMethod) {
+        self.config.registry.server().auth_failed(method);
+        self.audit(AuditEventKind::AuthAttempt {
+            user: user.to_string(),
+            method: method.name(),
+            accepted: false,
+        });
+    }
+
+    /// Drops the terminal task of a channel whose render side went away and closes it
+    fn close_channel(
+        &mut self,
+        channel: ChannelId,
+        session: &mut russh::server::Session,
+    ) -> Result<(), crate::Error> {
+        warn!("Terminal task for {channel:?} is gone, closing the channel");
+        self.channels.remove(&channel);
+        session.close(channel)?;
+        Ok(())
+    }
+}
+
+impl<T: ClientHandler> Drop for SshSessionHandler<T> {
+    fn drop(&mut self) {
+        self.audit(AuditEventKind::ConnectionClosed {
+            duration: self.connected.elapsed(),
+        });
+    }
 }
 
 enum ChannelState {
-    TerminalSession((UnboundedSender<TerminalInputs>, JoinHandle<()>, InputParser)),
+    Opened((ChannelWriteHalf<Msg>, ClientEnv)),
+    TerminalSession((Sender<TerminalInputs>, JoinHandle<()>, SharedDecoder)),
 }
 
 impl<T: ClientHandler> Handler for SshSessionHandler<T> {
     type Error = crate::Error;
 
 
-    async fn auth_none(&mut self, _user: &str) -> Result<russh::server::Auth, Self::Error> {
+    async fn auth_none(&mut self, user: &str) -> Result<russh::server::Auth, Self::Error> {
+        self.user = Some(user.to_string());
+        self.audit(AuditEventKind::AuthAttempt {
+            user: user.to_string(),
+            method: AuthMethod::None.name(),
+            accepted: true,
+        });
         Ok(russh::server::Auth::Accept)
     }
 
+    async fn auth_password(
+        &mut self,
+        user: &str,
+        _password: &str,
+    ) -> Result<russh::server::Auth, Self::Error> {
+        self.auth_failed(user, AuthMethod::Password);
+        Ok(russh::server::Auth::reject())
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        _public_key: &russh::keys::PublicKey,
+    ) -> Result<russh::server::Auth, Self::Error> {
+        self.auth_failed(user, AuthMethod::PublicKey);
+        Ok(russh::server::Auth::reject())
+    }
+
+    async fn auth_keyboard_interactive<'a>(
+        &'a mut self,
+        user: &str,
+        _submethods: &str,
+        _response: Option<russh::server::Response<'a>>,
+    ) -> Result<russh::server::Auth, Self::Error> {
+        self.auth_failed(user, AuthMethod::KeyboardInteractive);
+        Ok(russh::server::Auth::reject())
+    }
+
     async fn channel_open_session(
         &mut self,
-        _channel: russh::Channel<russh::server::Msg>,
+        channel: russh::Channel<russh::server::Msg>,
         _session: &mut russh::server::Session,
     ) -> Result<bool, Self::Error> {
-        Ok(self.handler.terminal_request() == Decision::Accept)
+        if lock(&self.handler).terminal_request() != Decision::Accept {
+            SessionMetrics::add(&self.config.registry.server().rejected_connections, 1);
+            return Ok(false);
+        }
+
+        let (_, writer) = channel.split();
+        self.channels
+            .insert(writer.id(), ChannelState::Opened((writer, ClientEnv::default())));
+        Ok(true)
     }
 
     async fn pty_request(
         &mut self,
         channel: ChannelId,
-        _term: &str,
+        term: &str,
         col_width: u32,
         row_height: u32,
-        _pix_width: u32,
-        _pix_height: u32,
-        _modes: &[(russh::Pty, u32)],
-        session: &mut russh::server::Session,
+        pix_width: u32,
+        pix_height: u32,
+        modes: &[(russh::Pty, u32)],
+        _session: &mut russh::server::Session,
     ) -> Result<(), Self::Error> {
+        let (writer, mut env) = match self.channels.remove(&channel) {
+            Some(ChannelState::Opened(opened)) => opened,
+            Some(state @ ChannelState::TerminalSession(_)) => {
+                self.channels.insert(channel, state);
+                return Err(crate::Error::PtyRequestTwice);
+            }
+            None => return Err(crate::Error::PtyRequestBeforeOpenRequest),
+        };
 
-        let session =
-            term::create_and_detach(col_width, row_height, &mut self.handler, session.handle(), channel).await?;
+        let guard = self
+            .config
+            .registry
+            .register(self.connection, self.user.clone(), self.addr);
+        env.set("TERM", term);
+        env.set_modes(TerminalModes::from_pty(modes));
+        let size = window_size(col_width, row_height, pix_width, pix_height);
+        let session = term::create_and_detach(
+            size,
+            &self.handler,
+            &self.config,
+            writer,
+            guard,
+            env,
+        )
+        .await?;
 
         self.channels
             .insert(channel, ChannelState::TerminalSession(session));
+        self.audit(AuditEventKind::PtyGranted {
+            term: term.to_string(),
+            width: col_width,
+            height: row_height,
+        });
+        Ok(())
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut russh::server::Session,
+    ) -> Result<(), Self::Error> {
+        let state = self
+            .channels
+            .get_mut(&channel)
+            .ok_or(crate::Error::UnknownChannel)?;
+
+        if !ClientEnv::accepts(variable_name) {
+            trace!("Refusing client env {variable_name}");
+            session.channel_failure(channel)?;
+            return Ok(());
+        }
+
+        match state {
+            ChannelState::Opened((_, env)) => env.set(variable_name, variable_value),
+            // Most clients only send their environment once the pty is up
+            ChannelState::TerminalSession((sender, _, _)) => {
+                let var = (variable_name.to_string(), variable_value.to_string());
+                if sender.send(TerminalInputs::Env(var)).await.is_err() {
+                    return self.close_channel(channel, session);
+                }
+            }
+        }
+
+        session.channel_success(channel)?;
+        Ok(())
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut russh::server::Session,
+    ) -> Result<(), Self::Error> {
+        self.audit(AuditEventKind::ExecRequest {
+            command: String::from_utf8_lossy(data).to_string(),
+        });
+        // Only interactive terminals are served
+        session.channel_failure(channel)?;
+        Ok(())
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut russh::server::Session,
+    ) -> Result<(), Self::Error> {
+        self.audit(AuditEventKind::SubsystemRequest {
+            name: name.to_string(),
+        });
+        session.channel_failure(channel)?;
         Ok(())
     }
 
@@ -71,7 +333,7 @@ impl<T: ClientHandler> Handler for SshSessionHandler<T> {
         &mut self,
         channel: ChannelId,
         data: &[u8],
-        _session: &mut russh::server::Session,
+        session: &mut russh::server::Session,
     ) -> Result<(), Self::Error> {
         let state = self
             .channels
@@ -79,16 +341,48 @@ impl<T: ClientHandler> Handler for SshSessionHandler<T> {
             .ok_or(crate::Error::UnknownChannel)?;
 
         trace!("Got data from client {data:?}");
+        SessionMetrics::add(&self.config.registry.server().bytes_in, data.len() as u64);
 
-        match state {
-            ChannelState::TerminalSession((sender, _, parser)) => {
-                parser.parse(
-                    data,
-                    |x| sender.send(TerminalInputs::Input(x)).unwrap(),
-                    true,
-                );
+        let ChannelState::TerminalSession((sender, _, decoder)) = state else {
+            return Err(crate::Error::UnknownChannel);
+        };
+
+        let mut events = lock(decoder).decode(data).into_iter();
+        while let Some(event) = events.next() {
+            let event = match sender.try_send(event) {
+                Ok(()) => continue,
+                Err(TrySendError::Closed(_)) => return self.close_channel(channel, session),
+                Err(TrySendError::Full(event)) => event,
+            };
+
+            match self.config.input_overflow {
+                InputOverflow::Drop => {
+                    // Losing these would leave an ESC stuck in the decoder or a query without answer
+                    let (signals, dropped): (Vec<_>, Vec<_>) =
+                        std::iter::once(event).chain(events).partition(TerminalInputs::is_signal);
+                    warn!("Input queue is full, dropping {} events", dropped.len());
+                    for signal in signals {
+                        if sender.send(signal).await.is_err() {
+                            return self.close_channel(channel, session);
+                        }
+                    }
+                    break;
+                }
+                InputOverflow::Coalesce => {
+                    for event in input::coalesce(std::iter::once(event).chain(events)) {
+                        if sender.send(event).await.is_err() {
+                            return self.close_channel(channel, session);
+                        }
+                    }
+                    break;
+                }
+                InputOverflow::Disconnect => {
+                    warn!("Input queue is full, disconnecting client");
+                    self.channels.remove(&channel);
+                    session.disconnect(Disconnect::ByApplication, "Too much input", "")?;
+                    break;
+                }
             }
-            _ => return Err(crate::Error::UnknownChannel),
         }
 
         Ok(())
@@ -99,22 +393,28 @@ impl<T: ClientHandler> Handler for SshSessionHandler<T> {
         channel: ChannelId,
         col_width: u32,
         row_height: u32,
-        _pix_width: u32,
-        _pix_height: u32,
-        _session: &mut russh::server::Session,
+        pix_width: u32,
+        pix_height: u32,
+        session: &mut russh::server::Session,
     ) -> Result<(), Self::Error> {
         let state = self
             .channels
             .get_mut(&channel)
             .ok_or(crate::Error::UnknownChannel)?;
 
-        match state {
-            ChannelState::TerminalSession((sender, _, _)) => {
-                sender
-                    .send(TerminalInputs::Resize((col_width, row_height)))
-                    .unwrap();
-            }
-            _ => return Err(crate::Error::UnknownChannel),
+        let ChannelState::TerminalSession((sender, _, _)) = state else {
+            return Err(crate::Error::UnknownChannel);
+        };
+
+        // Resizes are rare enough to always wait for room instead of going through the overflow policy
+        if sender
+            .send(TerminalInputs::Resize(window_size(
+                col_width, row_height, pix_width, pix_height,
+            )))
+            .await
+            .is_err()
+        {
+            return self.close_channel(channel, session);
         }
 
         Ok(())
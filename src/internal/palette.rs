@@ -0,0 +1,239 @@
+use std::ops::{Deref, DerefMut};
+
+use ratatui::{
+    backend::{Backend, ClearType, WindowSize},
+    buffer::Cell,
+    layout::{Position, Size},
+    style::Color,
+};
+use termwiz::caps::ColorLevel;
+
+/// The 16 ANSI colors with the RGB values xterm uses for them
+const ANSI: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/// Channel values of the 6x6x6 color cube in the 256 color palette
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Rewrites cell colors the client cannot show into the closest ones it can
+pub struct PaletteBackend<B> {
+    inner: B,
+    level: ColorLevel,
+}
+
+impl<B> PaletteBackend<B> {
+    pub fn new(inner: B, level: ColorLevel) -> Self {
+        Self { inner, level }
+    }
+
+    /// Cells already on screen keep their colors, clear the terminal after changing the level
+    pub fn set_level(&mut self, level: ColorLevel) {
+        self.level = level;
+    }
+}
+
+impl<B> Deref for PaletteBackend<B> {
+    type Target = B;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<B> DerefMut for PaletteBackend<B> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<B: Backend> Backend for PaletteBackend<B> {
+    type Error = B::Error;
+
+    fn draw<'a, I>(&mut self, content: I) -> Result<(), Self::Error>
+    where
+        I: Iterator<Item = (u16, u16, &'a Cell)>,
+    {
+        if self.level == ColorLevel::TrueColor {
+            return self.inner.draw(content);
+        }
+
+        let level = self.level;
+        let cells: Vec<_> = content
+            .map(|(x, y, cell)| {
+                let mut cell = cell.clone();
+                cell.fg = downsample(cell.fg, level);
+                cell.bg = downsample(cell.bg, level);
+                cell.underline_color = downsample(cell.underline_color, level);
+                (x, y, cell)
+            })
+            .collect();
+        self.inner
+            .draw(cells.iter().map(|(x, y, cell)| (*x, *y, cell)))
+    }
+
+    fn append_lines(&mut self, n: u16) -> Result<(), Self::Error> {
+        self.inner.append_lines(n)
+    }
+
+    fn hide_cursor(&mut self) -> Result<(), Self::Error> {
+        self.inner.hide_cursor()
+    }
+
+    fn show_cursor(&mut self) -> Result<(), Self::Error> {
+        self.inner.show_cursor()
+    }
+
+    fn get_cursor_position(&mut self) -> Result<Position, Self::Error> {
+        self.inner.get_cursor_position()
+    }
+
+    fn set_cursor_position<P: Into<Position>>(&mut self, position: P) -> Result<(), Self::Error> {
+        self.inner.set_cursor_position(position)
+    }
+
+    fn clear(&mut self) -> Result<(), Self::Error> {
+        self.inner.clear()
+    }
+
+    fn clear_region(&mut self, clear_type: ClearType) -> Result<(), Self::Error> {
+        self.inner.clear_region(clear_type)
+    }
+
+    fn size(&self) -> Result<Size, Self::Error> {
+        self.inner.size()
+    }
+
+    fn window_size(&mut self) -> Result<WindowSize, Self::Error> {
+        self.inner.window_size()
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+fn downsample(color: Color, level: ColorLevel) -> Color {
+    match (level, color) {
+        (ColorLevel::TrueColor, color) | (_, color @ Color::Reset) => color,
+        (ColorLevel::MonoChrome, _) => Color::Reset,
+        (ColorLevel::TwoFiftySix, Color::Rgb(r, g, b)) => Color::Indexed(nearest_indexed((r, g, b))),
+        (ColorLevel::Sixteen, Color::Rgb(r, g, b)) => nearest_ansi((r, g, b)),
+        (ColorLevel::Sixteen, Color::Indexed(i)) => match ANSI.get(i as usize) {
+            Some((color, _)) => *color,
+            None => nearest_ansi(indexed_rgb(i)),
+        },
+        (_, color) => color,
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+fn nearest_ansi(rgb: (u8, u8, u8)) -> Color {
+    ANSI.iter()
+        .min_by_key(|(_, ansi)| distance(rgb, *ansi))
+        .map(|(color, _)| *color)
+        .unwrap_or(Color::Reset)
+}
+
+/// Closest entry of the color cube or the gray ramp, the 16 ANSI colors differ too much
+/// between clients to be worth matching against
+fn nearest_indexed(rgb: (u8, u8, u8)) -> u8 {
+    let level = |c: u8| match c {
+        0..48 => 0,
+        48..115 => 1,
+        c => (c - 35) / 40,
+    };
+    let (r, g, b) = (level(rgb.0), level(rgb.1), level(rgb.2));
+    let cube = 16 + 36 * r + 6 * g + b;
+
+    let average = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+    let gray = 232 + ((average.saturating_sub(3)) / 10).min(23) as u8;
+
+    if distance(rgb, indexed_rgb(gray)) < distance(rgb, indexed_rgb(cube)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+fn indexed_rgb(i: u8) -> (u8, u8, u8) {
+    match i {
+        0..16 => ANSI[i as usize].1,
+        16..232 => {
+            let i = i - 16;
+            (
+                CUBE[(i / 36) as usize],
+                CUBE[(i / 6 % 6) as usize],
+                CUBE[(i % 6) as usize],
+            )
+        }
+        _ => {
+            let v = 8 + 10 * (i - 232);
+            (v, v, v)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn true_color_and_reset_are_kept() {
+        let rgb = Color::Rgb(12, 34, 56);
+        assert_eq!(downsample(rgb, ColorLevel::TrueColor), rgb);
+        for level in [ColorLevel::MonoChrome, ColorLevel::Sixteen, ColorLevel::TwoFiftySix] {
+            assert_eq!(downsample(Color::Reset, level), Color::Reset);
+        }
+    }
+
+    #[test]
+    fn monochrome_drops_colors() {
+        assert_eq!(downsample(Color::Red, ColorLevel::MonoChrome), Color::Reset);
+        assert_eq!(downsample(Color::Rgb(1, 2, 3), ColorLevel::MonoChrome), Color::Reset);
+    }
+
+    #[test]
+    fn palette_entries_map_to_themselves() {
+        for i in 16..=255 {
+            assert_eq!(nearest_indexed(indexed_rgb(i)), i, "{:?}", indexed_rgb(i));
+        }
+    }
+
+    #[test]
+    fn rgb_to_256_colors() {
+        assert_eq!(downsample(Color::Rgb(255, 0, 0), ColorLevel::TwoFiftySix), Color::Indexed(196));
+        assert_eq!(downsample(Color::Rgb(100, 100, 100), ColorLevel::TwoFiftySix), Color::Indexed(241));
+        // Named and indexed colors are already fine
+        assert_eq!(downsample(Color::Blue, ColorLevel::TwoFiftySix), Color::Blue);
+        assert_eq!(downsample(Color::Indexed(42), ColorLevel::TwoFiftySix), Color::Indexed(42));
+    }
+
+    #[test]
+    fn rgb_and_indexed_to_16_colors() {
+        assert_eq!(downsample(Color::Rgb(250, 10, 10), ColorLevel::Sixteen), Color::LightRed);
+        assert_eq!(downsample(Color::Rgb(10, 10, 10), ColorLevel::Sixteen), Color::Black);
+        assert_eq!(downsample(Color::Indexed(3), ColorLevel::Sixteen), Color::Yellow);
+        assert_eq!(downsample(Color::Indexed(21), ColorLevel::Sixteen), Color::Blue);
+        assert_eq!(downsample(Color::Indexed(255), ColorLevel::Sixteen), Color::Gray);
+    }
+}
@@ -1,22 +1,33 @@
-use std::mem::replace;
-use ratatui::{prelude::CrosstermBackend, Terminal};
-use russh::{ChannelId, CryptoVec, server::Handle};
-use tokio::{sync::mpsc::{UnboundedSender, unbounded_channel}, task::JoinHandle};
-use tracing::{trace, warn};
+use std::{mem::replace, sync::Arc};
+use ratatui::Terminal;
+use russh::{ChannelWriteHalf, CryptoVec, server::Msg};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::mpsc::{self, error::TrySendError, Sender},
+    task::JoinHandle,
+};
+use tracing::{trace, warn, Instrument};
 
+use crate::{
+    internal::{backend::TermwizBackend, palette::PaletteBackend},
+    registry::SessionMetrics,
+};
 
-pub type RatatuiTerminal = Terminal<CrosstermBackend<SinkTerminalHandle>>;
+/// How many flushed frames may wait for the writer before the terminal counts as congested
+const MAX_PENDING_FRAMES: usize = 2;
+
+pub type RatatuiTerminal = Terminal<PaletteBackend<TermwizBackend<SinkTerminalHandle>>>;
 
 pub struct SinkTerminalHandle {
     // The sink collects the data which is finally flushed to the handle.
     sink: CryptoVec,
 
-    tx: UnboundedSender<WriteMessage>,
+    tx: Sender<WriteMessage>,
     handle: Option<JoinHandle<()>>,
 }
 
 enum WriteMessage {
-    Close,
+    Close(u32),
     Write(CryptoVec),
 }
 
@@ -30,32 +41,49 @@ impl Drop for SinkTerminalHandle {
 }
 
 impl SinkTerminalHandle {
-    pub fn new(handle: Handle, channel_id: ChannelId) -> Self {
-        let (tx, mut rx) = unbounded_channel::<WriteMessage>();
+    pub fn new(channel: ChannelWriteHalf<Msg>, metrics: Arc<SessionMetrics>) -> Self {
+        let (tx, mut rx) = mpsc::channel::<WriteMessage>(MAX_PENDING_FRAMES);
         let handle = tokio::spawn(async move {
+            // Waits for the ssh window so a slow client stalls this task instead of growing buffers
+            let mut writer = channel.make_writer();
             loop {
                 let Some(data) = rx.recv().await else {
-                    warn!("Encountered an error while receiving write message message");
-                    continue;
+                    warn!("Write channel closed before the session was closed");
+                    return;
                 };
 
-                match data {
-                    WriteMessage::Close => {
-                        trace!("Closing session with client");
-                        if handle.close(channel_id).await.is_err() {
-                            warn!("Encounter error while terminating connection")
-                        };
+                let mut data = match data {
+                    WriteMessage::Close(exit_status) => {
+                        close_channel(&channel, exit_status).await;
                         return;
                     }
-                    WriteMessage::Write(data) => {
-                        trace!("Sending data to client");
-                        if let Err(err) = handle.data(channel_id, data).await {
-                            warn!("Encounter error {err:?} while sending data to connection")
-                        };
+                    WriteMessage::Write(data) => data,
+                };
+
+                // Coalesce whatever queued up while we were waiting on the client
+                let mut close = None;
+                while let Ok(next) = rx.try_recv() {
+                    match next {
+                        WriteMessage::Write(next) => data.extend(&next),
+                        WriteMessage::Close(exit_status) => {
+                            close = Some(exit_status);
+                            break;
+                        }
                     }
                 }
+
+                trace!("Sending {} bytes to client", data.len());
+                match writer.write_all(&data).await {
+                    Ok(()) => metrics.record_written(data.len()),
+                    Err(err) => warn!("Encounter error {err:?} while sending data to connection"),
+                };
+
+                if let Some(exit_status) = close {
+                    close_channel(&channel, exit_status).await;
+                    return;
+                }
             }
-        });
+        }.in_current_span());
 
         SinkTerminalHandle {
             sink: CryptoVec::new(),
@@ -64,8 +92,30 @@ impl SinkTerminalHandle {
         }
     }
 
-    pub async fn close(&mut self) -> Result<(), crate::Error> {
-        self.tx.send(WriteMessage::Close).unwrap();
+    /// Returns true when the writer is still busy with earlier frames and new draws should wait
+    pub fn is_congested(&self) -> bool {
+        !self.sink.is_empty() || self.tx.capacity() == 0
+    }
+
+    /// Resolves once the writer has room for another frame
+    pub async fn writable(&self) {
+        // The permit is released right away, we only care that a slot opened up
+        let _ = self.tx.reserve().await;
+    }
+
+    pub async fn close(&mut self, exit_status: u32) -> Result<(), crate::Error> {
+        if !self.sink.is_empty() {
+            let data = replace(&mut self.sink, CryptoVec::new());
+            self.tx
+                .send(WriteMessage::Write(data))
+                .await
+                .map_err(|_| crate::Error::SessionClosed)?;
+        }
+
+        self.tx
+            .send(WriteMessage::Close(exit_status))
+            .await
+            .map_err(|_| crate::Error::SessionClosed)?;
 
         let mut handle_option = self.handle.take();
         let handle = handle_option
@@ -76,7 +126,17 @@ impl SinkTerminalHandle {
     }
 }
 
-// The crossterm backend writes to the terminal handle.
+async fn close_channel(channel: &ChannelWriteHalf<Msg>, exit_status: u32) {
+    trace!("Closing session with client, exit status {exit_status}");
+    if channel.exit_status(exit_status).await.is_err() {
+        warn!("Encounter error while sending exit status")
+    };
+    if channel.close().await.is_err() {
+        warn!("Encounter error while terminating connection")
+    };
+}
+
+// The backend writes to the terminal handle.
 impl std::io::Write for SinkTerminalHandle {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.sink.extend(buf);
@@ -84,10 +144,20 @@ impl std::io::Write for SinkTerminalHandle {
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
+        if self.sink.is_empty() {
+            return Ok(());
+        }
+
         let old_vec = replace(&mut self.sink, CryptoVec::new());
-        if self.tx.send(WriteMessage::Write(old_vec)).is_err() {
-            return std::io::Result::Err(std::io::ErrorKind::BrokenPipe.into());
-        };
-        Ok(())
+        match self.tx.try_send(WriteMessage::Write(old_vec)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(WriteMessage::Write(old_vec))) => {
+                // Keep the bytes around, they go out together with the next flush
+                trace!("Writer is behind, holding back {} bytes", old_vec.len());
+                self.sink = old_vec;
+                Ok(())
+            }
+            Err(_) => Err(std::io::ErrorKind::BrokenPipe.into()),
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,27 +1,59 @@
-use std::{future::pending, io::Write, marker::PhantomData, time::Duration};
+use std::{
+    collections::HashMap,
+    future::{pending, Future},
+    io::Write,
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
 
-use crossterm::{
-    cursor,
-    terminal::{Clear, EnterAlternateScreen, LeaveAlternateScreen},
-    ExecutableCommand,
+use futures::FutureExt;
+use ratatui::{
+    backend::{Backend, WindowSize},
+    layout::{Position, Rect, Size},
+    TerminalOptions,
+};
+use russh::{server::Msg, ChannelWriteHalf};
+use termwiz::{
+    escape::csi::{DecPrivateModeCode, KittyKeyboardFlags},
+    input::{InputEvent, KeyEvent},
 };
-use ratatui::{layout::Rect, prelude::CrosstermBackend, TerminalOptions};
-use russh::{ChannelId, server::Handle};
-use termwiz::input::InputParser;
 use tokio::{
     select,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    task::JoinHandle,
-    time::{interval, Instant, Interval},
+    sync::{
+        mpsc::{self, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    task::{JoinHandle, JoinSet},
+    time::{interval, interval_at, sleep_until, Instant, Interval},
 };
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, info, trace, warn, Instrument};
 
 use crate::{
     api::{
-        term::{CallbackRez, EngineRef, SshTerminal},
+        caps::{Capabilities, TerminalModes},
+        keymap::KeyChord,
+        task::{SessionScope, TaskHandle},
+        term::{
+            CallbackRez, CursorShape, EngineRef, FrameStats, KeyboardEvent, MouseCapture, SshTerminal,
+            ViewportMode,
+        },
         ClientHandler,
     },
-    internal::sync_sink::{self, RatatuiTerminal},
+    internal::{
+        backend::TermwizBackend,
+        env::ClientEnv,
+        escape,
+        graphics::{self, GraphicsContext},
+        input::{InputDecoder, SharedDecoder},
+        lock,
+        palette::PaletteBackend,
+        sync_sink::{self, RatatuiTerminal, SinkTerminalHandle},
+        SessionConfig, SharedHandler,
+    },
+    audit::AuditEventKind,
+    registry::{SessionGuard, SessionMetrics},
 };
 
 //I hate this but its the most way sane way to not block main thread
@@ -29,26 +61,92 @@ pub struct RenderEngineApi<T: SshTerminal> {
     async_notifs_tx: UnboundedSender<T::MessageType>,
     async_notifs_rx: UnboundedReceiver<T::MessageType>,
 
+    tasks: JoinSet<T::MessageType>,
+    scope: watch::Sender<bool>,
+
     phantom: PhantomData<T>,
 
     size: Rect,
+    window: WindowSize,
 
     anim: Option<Interval>,
+    // Kept around so a paused animation can be resumed at the same rate
+    anim_period: Option<Duration>,
+
+    timers: HashMap<&'static str, Timer>,
+
+    metrics: Arc<SessionMetrics>,
+
+    // Escape sequences requested by the terminal, written out after each callback
+    output: String,
+
+    // Deadline for the client to answer the keyboard protocol query
+    keyboard_query: Option<Instant>,
+
+    quit_binding: Option<KeyChord>,
+    quit_message: String,
+
+    env: ClientEnv,
+    capabilities: Capabilities,
+}
+
+struct Timer {
+    deadline: Instant,
+    period: Option<Duration>,
 }
 
 impl<T: SshTerminal> RenderEngineApi<T> {
-    pub fn create(size: Rect) -> Self {
+    pub fn create(
+        size: Rect,
+        window: WindowSize,
+        metrics: Arc<SessionMetrics>,
+        config: &SessionConfig,
+        mut env: ClientEnv,
+    ) -> Self {
         let (ntx, nrx) = unbounded_channel();
+        let anim_period = T::DEFAULT_TPS
+            .map(|x| 1.0 / (x.get() as f32))
+            .map(Duration::from_secs_f32);
         Self {
             phantom: PhantomData,
             async_notifs_tx: ntx,
             async_notifs_rx: nrx,
-            anim: T::DEFAULT_TPS
-                .map(|x| 1.0 / (x.get() as f32))
-                .map(|x| interval(Duration::from_secs_f32(x))),
+            tasks: JoinSet::new(),
+            scope: watch::Sender::new(false),
+            anim: anim_period.map(interval),
+            anim_period,
+            timers: HashMap::new(),
+            metrics,
+            output: String::new(),
+            keyboard_query: None,
+            quit_binding: config.quit_binding,
+            quit_message: config.quit_message.clone(),
+            capabilities: env.capabilities(),
+            env,
             size,
+            window,
+        }
+    }
+
+    /// Reschedules a fired interval or forgets a one-shot timer
+    fn rearm_timer(&mut self, id: &'static str) {
+        let Some(timer) = self.timers.get_mut(id) else {
+            return;
+        };
+
+        match timer.period {
+            Some(period) => timer.deadline = Instant::now() + period,
+            None => {
+                self.timers.remove(id);
+            }
         }
     }
+
+    /// Stops everything tied to the session before the client gets disconnected
+    fn shutdown(&mut self) {
+        self.tasks.abort_all();
+        self.scope.send_replace(true);
+    }
 }
 
 impl<T: SshTerminal> EngineRef<T> for RenderEngineApi<T> {
@@ -59,65 +157,362 @@ impl<T: SshTerminal> EngineRef<T> for RenderEngineApi<T> {
     fn current_size(&mut self) -> Rect {
         self.size
     }
+
+    fn cell_size(&mut self) -> Option<Size> {
+        graphics::cell_size(self.window)
+    }
+
+    fn terminal_modes(&mut self) -> TerminalModes {
+        self.env.modes()
+    }
+
+    fn spawn<F>(&mut self, fut: F) -> TaskHandle
+    where
+        F: Future<Output = T::MessageType> + Send + 'static,
+    {
+        TaskHandle(self.tasks.spawn(fut))
+    }
+
+    fn scope(&mut self) -> SessionScope {
+        SessionScope(self.scope.subscribe())
+    }
+
+    fn set_tick_rate(&mut self, period: Duration) {
+        let period = period.max(Duration::from_millis(1));
+        self.anim_period = Some(period);
+        self.anim = Some(interval_at(Instant::now() + period, period));
+    }
+
+    fn pause_animation(&mut self) {
+        self.anim = None;
+    }
+
+    fn resume_animation(&mut self) {
+        if self.anim.is_some() {
+            return;
+        }
+
+        if let Some(period) = self.anim_period {
+            self.anim = Some(interval_at(Instant::now() + period, period));
+        }
+    }
+
+    fn set_timer(&mut self, id: &'static str, delay: Duration) {
+        self.timers.insert(
+            id,
+            Timer {
+                deadline: Instant::now() + delay,
+                period: None,
+            },
+        );
+    }
+
+    fn set_interval(&mut self, id: &'static str, period: Duration) {
+        let period = period.max(Duration::from_millis(1));
+        self.timers.insert(
+            id,
+            Timer {
+                deadline: Instant::now() + period,
+                period: Some(period),
+            },
+        );
+    }
+
+    fn cancel_timer(&mut self, id: &'static str) {
+        self.timers.remove(id);
+    }
+
+    fn frame_stats(&mut self) -> FrameStats {
+        FrameStats {
+            drawn: self.metrics.frames_drawn.load(Ordering::Relaxed),
+            skipped: self.metrics.frames_skipped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn set_mouse_capture(&mut self, capture: MouseCapture) {
+        escape::mouse_capture(&mut self.output, capture);
+    }
+
+    fn set_cursor_shape(&mut self, shape: CursorShape) {
+        escape::cursor_shape(&mut self.output, shape);
+    }
+
+    fn set_title(&mut self, title: &str) {
+        escape::title(&mut self.output, title);
+    }
+
+    fn copy_to_clipboard(&mut self, text: &str) {
+        escape::clipboard(&mut self.output, text);
+    }
+
+    fn bell(&mut self) {
+        self.output.push('\x07');
+    }
+
+    fn notify(&mut self, title: &str, body: &str) {
+        escape::notify(&mut self.output, title, body, self.env.rxvt_notifications());
+    }
+
+    fn enable_keyboard_enhancement(&mut self, flags: KittyKeyboardFlags) {
+        escape::push_keyboard_flags(&mut self.output, flags);
+        self.keyboard_query = Some(Instant::now() + KEYBOARD_QUERY_TIMEOUT);
+    }
+
+    fn capabilities(&mut self) -> Capabilities {
+        self.capabilities.clone()
+    }
+
+    fn check_quit(&mut self, key: &KeyEvent) -> Option<CallbackRez> {
+        self.quit_binding
+            .filter(|binding| binding.matches(key.key, key.modifiers))
+            .map(|_| CallbackRez::Terminate(self.quit_message.clone()))
+    }
 }
 
+/// How long a client gets to answer the keyboard protocol query before we fall back to legacy keys
+const KEYBOARD_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
 pub enum TerminalInputs {
-    Resize((u32, u32)),
-    Input(termwiz::input::InputEvent),
+    Resize(WindowSize),
+    Input(InputEvent),
+    Key(KeyboardEvent),
+    Focus(bool),
+    /// The client reported its kitty keyboard flags
+    KeyboardSupport,
+    /// The client answered the primary device attributes request
+    DeviceAttributes,
+    /// The decoder holds the start of an escape sequence until more data or the escape timeout
+    EscapePending,
+    /// Environment variable the client sent after the session started
+    Env((String, String)),
+}
+
+impl TerminalInputs {
+    /// Replies and decoder state rather than something the user typed, these survive a full input queue
+    pub fn is_signal(&self) -> bool {
+        matches!(
+            self,
+            TerminalInputs::KeyboardSupport
+                | TerminalInputs::DeviceAttributes
+                | TerminalInputs::EscapePending
+        )
+    }
 }
 
+/// Modes every session turns on at start and off again on exit
+const SESSION_MODES: [DecPrivateModeCode; 2] = [
+    DecPrivateModeCode::BracketedPaste,
+    DecPrivateModeCode::FocusTracking,
+];
+
 pub async fn create_and_detach<H: ClientHandler>(
-    width: u32,
-    height: u32,
-    session_handler: &mut H,
-    handle: Handle,
-    channel_id: ChannelId
-) -> Result<(UnboundedSender<TerminalInputs>, JoinHandle<()>, InputParser), crate::Error> {
-    let mut backend = CrosstermBackend::new(sync_sink::SinkTerminalHandle::new(handle, channel_id));
-    backend.execute(EnterAlternateScreen)?;
-    backend.execute(cursor::Hide)?;
-    backend.execute(Clear(crossterm::terminal::ClearType::All))?;
+    size: WindowSize,
+    session_handler: &SharedHandler<H>,
+    config: &Arc<SessionConfig>,
+    channel: ChannelWriteHalf<Msg>,
+    guard: SessionGuard,
+    mut env: ClientEnv,
+) -> Result<(Sender<TerminalInputs>, JoinHandle<()>, SharedDecoder), crate::Error> {
+    let span = guard.span.clone();
+    let _entered = span.enter();
+
+    let mut backend = TermwizBackend::new(
+        sync_sink::SinkTerminalHandle::new(channel, guard.metrics.clone()),
+        env.render_caps()?,
+        size,
+    );
+
+    let viewport = H::TerminalHandler::VIEWPORT;
+    let mut modes = String::new();
+    if viewport == ViewportMode::Fullscreen {
+        escape::dec_mode(&mut modes, DecPrivateModeCode::ClearAndEnableAlternateScreen, true);
+    }
+    for code in SESSION_MODES {
+        escape::dec_mode(&mut modes, code, true);
+    }
+    backend.write_all(modes.as_bytes())?;
+
+    let area = Rect::from((Position::ORIGIN, size.columns_rows));
+    let viewport = match viewport {
+        ViewportMode::Fullscreen => {
+            backend.hide_cursor()?;
+            backend.clear()?;
+            ratatui::Viewport::Fixed(area)
+        }
+        ViewportMode::Inline(lines) => {
+            backend.hide_cursor()?;
+            // Ratatui makes room for the viewport from where the cursor is
+            backend.set_cursor_position((0, area.height.saturating_sub(1)))?;
+            ratatui::Viewport::Inline(lines)
+        }
+        ViewportMode::Streaming => {
+            backend.set_streaming(true);
+            ratatui::Viewport::Fixed(area)
+        }
+    };
 
     let term = RatatuiTerminal::with_options(
-        backend,
-        TerminalOptions {
-            viewport: ratatui::Viewport::Fixed(Rect {
-                x: 0,
-                y: 0,
-                width: width as u16,
-                height: height as u16,
-            }),
-        },
+        PaletteBackend::new(backend, env.capabilities().color),
+        TerminalOptions { viewport },
     )?;
 
-    let (sender, receiver) = unbounded_channel();
-    let handler_term = session_handler.new_terminal();
-    let join_handle = tokio::task::spawn(dispatch::<H>(receiver, handler_term, term));
-    Ok((sender, join_handle, InputParser::new()))
+    let (sender, receiver) = mpsc::channel(config.input_queue);
+    let decoder = Arc::new(Mutex::new(InputDecoder::new(env.modes())));
+    let join_handle = tokio::task::spawn(
+        dispatch::<H>(
+            receiver,
+            decoder.clone(),
+            term,
+            session_handler.clone(),
+            config.clone(),
+            guard,
+            env,
+        )
+        .in_current_span(),
+    );
+    Ok((sender, join_handle, decoder))
 }
 
 async fn dispatch<H: ClientHandler>(
-    input: UnboundedReceiver<TerminalInputs>,
-    handler: H::TerminalHandler,
-    term: RatatuiTerminal,
+    input: Receiver<TerminalInputs>,
+    decoder: SharedDecoder,
+    mut term: RatatuiTerminal,
+    session_handler: SharedHandler<H>,
+    config: Arc<SessionConfig>,
+    guard: SessionGuard,
+    env: ClientEnv,
 ) {
     debug!("Dispatching new terminal session");
-    let Err(error) = dispatch_inner::<H>(input, handler, term).await else {
-        info!("Session ended without errors");
-        return;
+    let rez = AssertUnwindSafe(async {
+        // Inside the unwind guard so a panicking handler ends the session like a panicking draw
+        let handler = lock(&session_handler).new_terminal();
+        dispatch_inner::<H>(
+            input,
+            decoder,
+            handler,
+            &mut term,
+            &config,
+            &guard.metrics,
+            env,
+        )
+        .await
+    })
+        .catch_unwind()
+        .await;
+
+    let (reason, error) = match rez {
+        Ok(Ok(())) => {
+            info!("Session ended without errors");
+            ("terminated".to_string(), None)
+        }
+        Ok(Err(crate::Error::SessionClosed)) => {
+            info!("Client went away");
+            ("client disconnected".to_string(), None)
+        }
+        Ok(Err(error)) => {
+            warn!("Error while handling session {error:?}");
+            abort::<H>(&mut term, &config).await;
+            (format!("error: {error}"), Some(error))
+        }
+        Err(panic) => {
+            let reason = panic
+                .downcast_ref::<&str>()
+                .map(|x| x.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            warn!("Terminal panicked: {reason}");
+            abort::<H>(&mut term, &config).await;
+            (format!("panic: {reason}"), Some(crate::Error::TerminalPanic(reason)))
+        }
     };
 
-    warn!("Error while handling session {error:?}");
+    config.audit(
+        guard.connection,
+        guard.addr,
+        AuditEventKind::SessionEnded {
+            duration: guard.started.elapsed(),
+            reason,
+        },
+    );
+
+    if let Some(error) = error {
+        lock(&session_handler).on_error(&error);
+    }
+}
+
+/// Best effort [`farewell`] for a session that failed, the channel is closed even if the client cannot be restored
+async fn abort<H: ClientHandler>(term: &mut RatatuiTerminal, config: &SessionConfig) {
+    // A draw that panicked may have placed images, they must not end up in another session
+    graphics::take_placements();
+    let viewport = H::TerminalHandler::VIEWPORT;
+    if let Err(error) = farewell(term, viewport, &config.panic_message, 1).await {
+        warn!("Could not restore client terminal {error:?}");
+        let _ = term.backend_mut().writer_mut().close(1).await;
+    }
+}
+
+/// Puts the client terminal back the way we found it, prints a message and closes the channel
+async fn farewell(
+    term: &mut RatatuiTerminal,
+    viewport: ViewportMode,
+    msg: &str,
+    exit_status: u32,
+) -> Result<(), crate::Error> {
+    if let ViewportMode::Inline(_) = viewport {
+        // Continue below the viewport so the last frame stays in the scrollback
+        let area = term.get_frame().area();
+        term.set_cursor_position((0, area.bottom().saturating_sub(1)))?;
+        term.backend_mut().write_all(b"\r\n")?;
+    }
+    term.show_cursor()?;
+
+    let mut reset = String::new();
+    escape::mouse_capture(&mut reset, MouseCapture::Off);
+    escape::cursor_shape(&mut reset, CursorShape::Default);
+    escape::pop_keyboard_flags(&mut reset);
+    for code in SESSION_MODES {
+        escape::dec_mode(&mut reset, code, false);
+    }
+    if viewport == ViewportMode::Fullscreen {
+        escape::dec_mode(&mut reset, DecPrivateModeCode::ClearAndEnableAlternateScreen, false);
+    }
+
+    let backend = term.backend_mut();
+    backend.write_all(reset.as_bytes())?;
+
+    backend.write_all(msg.replace("\n", "\n\r").as_bytes())?;
+    backend.write_all(b"\n\r")?;
+    backend.flush()?;
+
+    backend.writer_mut().close(exit_status).await
 }
 
 async fn dispatch_inner<H: ClientHandler>(
-    mut input: UnboundedReceiver<TerminalInputs>,
+    mut input: Receiver<TerminalInputs>,
+    decoder: SharedDecoder,
     mut handler: H::TerminalHandler,
-    mut term: RatatuiTerminal,
+    term: &mut RatatuiTerminal,
+    config: &SessionConfig,
+    metrics: &Arc<SessionMetrics>,
+    env: ClientEnv,
 ) -> Result<(), crate::Error> {
+    let window = term.backend_mut().window_size()?;
     let mut engine: RenderEngineApi<H::TerminalHandler> =
-        RenderEngineApi::create(term.get_frame().area());
+        RenderEngineApi::create(term.get_frame().area(), window, metrics.clone(), config, env);
     let mut recv_buf = Vec::new();
+    let frame_time = config
+        .max_fps
+        .map(|x| Duration::from_secs_f64(1.0 / x.get() as f64))
+        .unwrap_or_default();
+    let mut last_frame: Option<Instant> = None;
+    // Set when a draw was put off because of the frame rate limit or because the client could not keep up.
+    // Starts out set so the first frame goes out without waiting for input
+    let mut redraw_pending = true;
+    // When to stop waiting for the rest of an escape sequence the decoder is holding on to
+    let mut escape_deadline: Option<Instant> = None;
+    // What the backend currently renders for
+    let mut capabilities = engine.capabilities.clone();
     loop {
         trace!("New client wait loop");
         recv_buf.clear();
@@ -130,24 +525,29 @@ async fn dispatch_inner<H: ClientHandler>(
 
                 let mut current_state = CallbackRez::Continue;
                 for i in recv_buf.iter().rev() {
-                    let TerminalInputs::Resize((width, height)) = i else {
+                    let TerminalInputs::Resize(size) = i else {
                         continue;
                     };
 
-                    let width = *width as u16;
-                    let height = *height as u16;
-                    let rect = Rect { x: 0, y: 0, width, height };
-                    term.resize(rect)?;
-                    current_state = current_state.pick(handler.on_resize(&mut engine, width , height));
-                    engine.size = rect;
+                    term.backend_mut().set_size(*size);
+                    term.resize(Rect::from((Position::ORIGIN, size.columns_rows)))?;
+                    // Inline viewports only take up part of the window
+                    engine.size = term.get_frame().area();
+                    engine.window = *size;
+                    current_state = current_state.pick(handler.on_resize(&mut engine, *size));
                     break;
                 }
 
-                while let Some(i) = recv_buf.pop() {
-                    let TerminalInputs::Input(input) = i else {
+                for i in recv_buf.drain(..) {
+                    if let TerminalInputs::EscapePending = i {
+                        // Counted from the ESC, a client dribbling out a sequence does not get to put it off
+                        escape_deadline.get_or_insert(Instant::now() + config.escape_timeout);
                         continue;
-                    };
-                    current_state = current_state.pick(handler.on_input(&mut engine, input));
+                    }
+                    current_state = current_state.pick(deliver(&mut handler, &mut engine, i));
+                }
+                if escape_deadline.is_some() && !lock(&decoder).is_holding() {
+                    escape_deadline = None;
                 }
 
                 current_state
@@ -158,40 +558,103 @@ async fn dispatch_inner<H: ClientHandler>(
                 };
                 handler.on_message(&mut engine, out)
             },
+            Some(rez) = engine.tasks.join_next(), if !engine.tasks.is_empty() => {
+                match rez {
+                    Ok(out) => handler.on_message(&mut engine, out),
+                    Err(error) if error.is_panic() => {
+                        // Let the panic reach the session the same way a callback panic would
+                        std::panic::resume_unwind(error.into_panic())
+                    }
+                    Err(_) => continue,
+                }
+            },
             anim = animation_interval(&mut engine.anim) => {
                 trace!("Animation wake {anim:?}");
                 handler.on_animation(&mut engine)
             }
+            id = next_timer(&engine.timers), if !engine.timers.is_empty() => {
+                trace!("Timer {id} fired");
+                engine.rearm_timer(id);
+                handler.on_timer(&mut engine, id)
+            }
+            _ = deadline(escape_deadline), if escape_deadline.is_some() => {
+                trace!("Escape timeout ran out");
+                escape_deadline = None;
+                let flushed = lock(&decoder).flush();
+                flushed.into_iter().fold(CallbackRez::Continue, |state, i| {
+                    state.pick(deliver(&mut handler, &mut engine, i))
+                })
+            }
+            _ = deadline(engine.keyboard_query), if engine.keyboard_query.is_some() => {
+                debug!("Client did not answer the keyboard protocol query");
+                engine.keyboard_query = None;
+                handler.on_keyboard_enhancement(&mut engine, false)
+            }
+            _ = next_frame_slot(term.backend().writer(), last_frame.map(|x| x + frame_time)), if redraw_pending => {
+                trace!("Frame slot open");
+                // Push out whatever was held back before drawing on top of it
+                term.backend_mut().flush()?;
+                redraw_pending = false;
+                CallbackRez::PushToRenderer
+            }
         };
 
         trace!("Loop result: {state:?}");
 
+        if !engine.output.is_empty() {
+            let backend = term.backend_mut();
+            backend.write_all(engine.output.as_bytes())?;
+            backend.flush()?;
+            engine.output.clear();
+        }
+
+        if engine.capabilities != capabilities {
+            capabilities = engine.capabilities.clone();
+            debug!("Rendering for {capabilities:?}");
+            let backend = term.backend_mut();
+            backend.set_level(capabilities.color);
+            backend.set_caps(engine.env.render_caps()?);
+            // Repaint everything, cells already on screen were drawn for the old terminal
+            term.clear()?;
+        }
+
         match state {
+            CallbackRez::PushToRenderer
+                if term.backend().writer().is_congested()
+                    || last_frame.is_some_and(|x| x + frame_time > Instant::now()) =>
+            {
+                trace!("Coalescing frame into the next slot");
+                SessionMetrics::add(&metrics.frames_skipped, 1);
+                redraw_pending = true;
+            }
             CallbackRez::PushToRenderer => {
                 let inner = &mut handler;
+                let started = Instant::now();
+                if capabilities.synchronized_output {
+                    term.backend_mut().begin_update()?;
+                }
+                let streaming = H::TerminalHandler::VIEWPORT == ViewportMode::Streaming;
+                if streaming {
+                    // Print the whole frame again instead of what changed since the last one
+                    term.clear()?;
+                }
+                let window = term.backend_mut().window_size()?;
+                graphics::set_context(GraphicsContext::new(&capabilities, window, streaming));
                 //TODO: benchmark if block_in_place would make a difference
                 let rez = term.draw(move |x| {
                     inner.draw(x);
                 });
                 trace!("Frame finished");
+                let now = Instant::now();
+                last_frame = Some(now);
+                metrics.record_draw(now - started);
                 if let Err(error) = rez {
                     warn!("Error while rendering: {error:?}");
                 }
             }
             CallbackRez::Terminate(msg) => {
-                //TODO make it less hacky
-                term.show_cursor().unwrap();
-
-                let backend = term.backend_mut();
-                backend.execute(cursor::Show)?;
-                backend.execute(LeaveAlternateScreen)?;
-
-                backend.write_all(msg.replace("\n", "\n\r").as_bytes())?;
-                backend.write_all(b"\n\r")?;
-                backend.flush()?;
-
-                backend.writer_mut().close().await?;
-
+                engine.shutdown();
+                farewell(term, H::TerminalHandler::VIEWPORT, &msg, 0).await?;
                 return Ok(());
             }
             _ => {}
@@ -199,6 +662,71 @@ async fn dispatch_inner<H: ClientHandler>(
     }
 }
 
+/// Hands a single input to the matching callback
+fn deliver<T: SshTerminal>(
+    handler: &mut T,
+    engine: &mut RenderEngineApi<T>,
+    input: TerminalInputs,
+) -> CallbackRez {
+    let rez = match input {
+        TerminalInputs::Resize(_) | TerminalInputs::EscapePending => return CallbackRez::Continue,
+        TerminalInputs::Env((name, value)) => {
+            engine.env.set(&name, &value);
+            let capabilities = engine.env.capabilities();
+            if capabilities == engine.capabilities {
+                return CallbackRez::Continue;
+            }
+            debug!("Client capabilities {capabilities:?}");
+            engine.capabilities = capabilities;
+            // Streaming prints every frame below the last one, the next frame picks the change up
+            return match T::VIEWPORT {
+                ViewportMode::Streaming => CallbackRez::Continue,
+                _ => CallbackRez::PushToRenderer,
+            };
+        }
+        TerminalInputs::Input(InputEvent::Paste(text)) => handler.on_paste(engine, text),
+        TerminalInputs::Input(InputEvent::Key(key)) => handler.on_key(engine, key.into()),
+        TerminalInputs::Input(input) => handler.on_input(engine, input),
+        TerminalInputs::Key(key) => handler.on_key(engine, key),
+        TerminalInputs::Focus(focused) => handler.on_focus(engine, focused),
+        // The keyboard report comes before the device attributes, whichever arrives first settles the query
+        TerminalInputs::KeyboardSupport | TerminalInputs::DeviceAttributes => {
+            if engine.keyboard_query.take().is_none() {
+                return CallbackRez::Continue;
+            }
+            let enabled = matches!(input, TerminalInputs::KeyboardSupport);
+            debug!("Keyboard enhancement enabled: {enabled}");
+            handler.on_keyboard_enhancement(engine, enabled)
+        }
+    };
+    SessionMetrics::add(&engine.metrics.input_events, 1);
+    rez
+}
+
+/// Resolves once the frame rate limit allows another draw and the writer has room for it
+async fn next_frame_slot(writer: &SinkTerminalHandle, slot: Option<Instant>) {
+    if let Some(slot) = slot {
+        sleep_until(slot).await;
+    }
+    writer.writable().await;
+}
+
+async fn deadline(at: Option<Instant>) {
+    match at {
+        Some(at) => sleep_until(at).await,
+        None => pending().await,
+    }
+}
+
+async fn next_timer(timers: &HashMap<&'static str, Timer>) -> &'static str {
+    let Some((id, timer)) = timers.iter().min_by_key(|(_, timer)| timer.deadline) else {
+        return pending().await;
+    };
+
+    sleep_until(timer.deadline).await;
+    id
+}
+
 async fn animation_interval(interval: &mut Option<Interval>) -> Instant {
     if let Some(animation) = interval.as_mut() {
         animation.tick().await
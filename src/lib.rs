@@ -1,4 +1,10 @@
-use std::{marker::PhantomData, net::SocketAddr, sync::Arc};
+use std::{
+    marker::PhantomData,
+    net::SocketAddr,
+    num::NonZero,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
 use russh::{
     keys::PrivateKey,
@@ -8,17 +14,29 @@ use russh::{
 use tracing::info;
 
 pub mod api;
+pub mod audit;
 mod error;
+#[cfg(feature = "prometheus")]
+mod exporter;
 mod internal;
+pub mod registry;
 pub mod util;
 
 pub use error::Error;
 
-use crate::{api::ClientHandler, internal::SshSessionHandler};
+use crate::{
+    api::{keymap::KeyChord, ClientHandler, InputOverflow},
+    audit::AuditSink,
+    internal::{SessionConfig, SshSessionHandler},
+    registry::SessionRegistry,
+};
 
 pub struct SshDanceBuilder<H: ClientHandler> {
     socket: SocketAddr,
     key_pair: Vec<PrivateKey>,
+    session: SessionConfig,
+    #[cfg(feature = "prometheus")]
+    metrics_addr: Option<SocketAddr>,
 
     data: PhantomData<H>,
 }
@@ -32,6 +50,9 @@ impl<H: ClientHandler> SshDanceBuilder<H> {
                 russh::keys::Algorithm::Ed25519,
             )
             .unwrap()],
+            session: SessionConfig::default(),
+            #[cfg(feature = "prometheus")]
+            metrics_addr: None,
             data: PhantomData,
         }
     }
@@ -41,7 +62,76 @@ impl<H: ClientHandler> SshDanceBuilder<H> {
         self
     }
 
+    /// Sets how many input events a session may buffer and what happens once they do not fit
+    pub fn set_input_queue(mut self, capacity: usize, overflow: InputOverflow) -> Self {
+        self.session.input_queue = capacity.max(1);
+        self.session.input_overflow = overflow;
+        self
+    }
+
+    /// Sets the message shown to a client whose terminal panicked or failed with an error
+    pub fn set_panic_message(mut self, message: impl Into<String>) -> Self {
+        self.session.panic_message = message.into();
+        self
+    }
+
+    /// Key that ends a session whose terminal does not handle it, `None` turns it off.
+    /// Defaults to Ctrl+D
+    pub fn set_quit_binding(mut self, binding: Option<KeyChord>) -> Self {
+        self.session.quit_binding = binding;
+        self
+    }
+
+    /// Message printed when the quit binding ends a session
+    pub fn set_quit_message(mut self, message: impl Into<String>) -> Self {
+        self.session.quit_message = message.into();
+        self
+    }
+
+    /// Caps how many frames per second a session sends, redraws in between are folded into the
+    /// next frame. `None` draws on every request
+    pub fn set_max_fps(mut self, max_fps: Option<NonZero<u32>>) -> Self {
+        self.session.max_fps = max_fps;
+        self
+    }
+
+    /// How long a lone Escape waits for the rest of an escape sequence before it counts as the
+    /// Escape key. Raise it for clients on slow links that split sequences between packets
+    pub fn set_escape_timeout(mut self, timeout: Duration) -> Self {
+        self.session.escape_timeout = timeout;
+        self
+    }
+
+    /// Serves the server statistics in the Prometheus text format on `addr`, bind it to a local
+    /// address unless you want the whole world to scrape it
+    #[cfg(feature = "prometheus")]
+    pub fn set_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Sends connection, authentication and session events to `sink`
+    pub fn set_audit_sink(mut self, sink: impl AuditSink) -> Self {
+        self.session.audit = Some(Arc::new(sink));
+        self
+    }
+
+    /// Handle to the sessions of this server and their render metrics, stays valid after [`Self::run`]
+    pub fn registry(&self) -> SessionRegistry {
+        self.session.registry.clone()
+    }
+
     pub async fn run(self) -> Result<(), crate::Error> {
+        #[cfg(feature = "prometheus")]
+        if let Some(addr) = self.metrics_addr {
+            let registry = self.registry();
+            tokio::spawn(async move {
+                if let Err(err) = exporter::serve(addr, registry).await {
+                    tracing::warn!("Metrics exporter stopped {err:?}");
+                }
+            });
+        }
+
         let config = Config {
             inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
             auth_rejection_time: std::time::Duration::from_secs(3),
@@ -55,12 +145,16 @@ impl<H: ClientHandler> SshDanceBuilder<H> {
             ..Default::default()
         };
 
-        let mut server: SshSiteServer<H> = SshSiteServer { data: PhantomData };
+        let mut server: SshSiteServer<H> = SshSiteServer {
+            session: Arc::new(self.session),
+            data: PhantomData,
+        };
         server.run(config, self.socket).await
     }
 }
 
 pub(crate) struct SshSiteServer<H: ClientHandler> {
+    session: Arc<SessionConfig>,
     data: PhantomData<H>,
 }
 
@@ -76,6 +170,12 @@ impl<H: ClientHandler> Server for SshSiteServer<H> {
 
     fn new_client(&mut self, addr: Option<std::net::SocketAddr>) -> Self::Handler {
         info!("New client connected {addr:?}");
-        SshSessionHandler::create(addr)
+        let connection = self
+            .session
+            .registry
+            .server()
+            .connections
+            .fetch_add(1, Ordering::Relaxed);
+        SshSessionHandler::create(addr, connection, self.session.clone())
     }
 }
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing::{info_span, Span};
+
+/// Live view of all terminal sessions of a server, get one from [`crate::SshDanceBuilder::registry`]
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    inner: Arc<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Entry>>,
+    server: Arc<ServerMetrics>,
+}
+
+struct Entry {
+    connection: u64,
+    user: Option<String>,
+    addr: Option<SocketAddr>,
+    started: SystemTime,
+    metrics: Arc<SessionMetrics>,
+}
+
+/// Server wide totals since the server started
+#[derive(Debug, Clone)]
+pub struct ServerStats {
+    pub active_sessions: usize,
+    pub connections: u64,
+    /// Session channels turned down by [`crate::api::ClientHandler::terminal_request`]
+    pub rejected_connections: u64,
+    /// Failed authentication attempts by method name
+    pub auth_failures: Vec<(&'static str, u64)>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub draw_time: HistogramSnapshot,
+}
+
+/// Cumulative histogram, each bucket counts observations less than or equal to its bound
+#[derive(Debug, Clone)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<(Duration, u64)>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+/// Snapshot of a single terminal session
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    /// Same id the audit log uses for the connection this session runs on
+    pub connection: u64,
+    pub user: Option<String>,
+    pub addr: Option<SocketAddr>,
+    pub started: SystemTime,
+    pub frames_drawn: u64,
+    pub frames_skipped: u64,
+    pub bytes_written: u64,
+    pub input_events: u64,
+    pub average_draw_time: Duration,
+}
+
+impl SessionRegistry {
+    /// All sessions that are currently running
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let sessions = self
+            .inner
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        sessions
+            .iter()
+            .map(|(id, entry)| {
+                let metrics = &entry.metrics;
+                let frames_drawn = metrics.frames_drawn.load(Ordering::Relaxed);
+                let draw_nanos = metrics.draw_nanos.load(Ordering::Relaxed);
+                SessionInfo {
+                    id: *id,
+                    connection: entry.connection,
+                    user: entry.user.clone(),
+                    addr: entry.addr,
+                    started: entry.started,
+                    frames_drawn,
+                    frames_skipped: metrics.frames_skipped.load(Ordering::Relaxed),
+                    bytes_written: metrics.bytes_written.load(Ordering::Relaxed),
+                    input_events: metrics.input_events.load(Ordering::Relaxed),
+                    average_draw_time: Duration::from_nanos(
+                        draw_nanos.checked_div(frames_drawn).unwrap_or_default(),
+                    ),
+                }
+            })
+            .collect()
+    }
+
+    pub fn server_stats(&self) -> ServerStats {
+        let server = &self.inner.server;
+        ServerStats {
+            active_sessions: self.len(),
+            connections: server.connections.load(Ordering::Relaxed),
+            rejected_connections: server.rejected_connections.load(Ordering::Relaxed),
+            auth_failures: AuthMethod::ALL
+                .iter()
+                .map(|method| {
+                    let failures = server.auth_failures[*method as usize].load(Ordering::Relaxed);
+                    (method.name(), failures)
+                })
+                .collect(),
+            bytes_in: server.bytes_in.load(Ordering::Relaxed),
+            bytes_out: server.bytes_out.load(Ordering::Relaxed),
+            draw_time: server.draw_time.snapshot(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn server(&self) -> &ServerMetrics {
+        &self.inner.server
+    }
+
+    pub(crate) fn register(
+        &self,
+        connection: u64,
+        user: Option<String>,
+        addr: Option<SocketAddr>,
+    ) -> SessionGuard {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let metrics = Arc::new(SessionMetrics {
+            frames_drawn: AtomicU64::new(0),
+            frames_skipped: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            input_events: AtomicU64::new(0),
+            draw_nanos: AtomicU64::new(0),
+            server: self.inner.server.clone(),
+        });
+        let span = info_span!(
+            "session",
+            id,
+            connection,
+            user = user.as_deref().unwrap_or_default(),
+            addr = ?addr,
+        );
+        self.inner
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                id,
+                Entry {
+                    connection,
+                    user,
+                    addr,
+                    started: SystemTime::now(),
+                    metrics: metrics.clone(),
+                },
+            );
+
+        SessionGuard {
+            id,
+            connection,
+            addr,
+            started: Instant::now(),
+            metrics,
+            span,
+            registry: self.clone(),
+        }
+    }
+}
+
+/// Counters updated by the session while it runs
+pub(crate) struct SessionMetrics {
+    pub frames_drawn: AtomicU64,
+    pub frames_skipped: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub input_events: AtomicU64,
+    pub draw_nanos: AtomicU64,
+    server: Arc<ServerMetrics>,
+}
+
+impl SessionMetrics {
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn record_draw(&self, time: Duration) {
+        Self::add(&self.frames_drawn, 1);
+        Self::add(&self.draw_nanos, time.as_nanos() as u64);
+        self.server.draw_time.observe(time);
+    }
+
+    pub fn record_written(&self, bytes: usize) {
+        Self::add(&self.bytes_written, bytes as u64);
+        Self::add(&self.server.bytes_out, bytes as u64);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum AuthMethod {
+    None,
+    Password,
+    PublicKey,
+    KeyboardInteractive,
+}
+
+impl AuthMethod {
+    const ALL: [AuthMethod; 4] = [
+        AuthMethod::None,
+        AuthMethod::Password,
+        AuthMethod::PublicKey,
+        AuthMethod::KeyboardInteractive,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AuthMethod::None => "none",
+            AuthMethod::Password => "password",
+            AuthMethod::PublicKey => "publickey",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+        }
+    }
+}
+
+/// Counters shared by every connection of a server
+#[derive(Default)]
+pub(crate) struct ServerMetrics {
+    pub connections: AtomicU64,
+    pub rejected_connections: AtomicU64,
+    pub auth_failures: [AtomicU64; AuthMethod::ALL.len()],
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub draw_time: Histogram,
+}
+
+impl ServerMetrics {
+    pub fn auth_failed(&self, method: AuthMethod) {
+        SessionMetrics::add(&self.auth_failures[method as usize], 1);
+    }
+}
+
+/// Upper bounds of the draw time buckets in microseconds
+const DRAW_TIME_BUCKETS: [u64; 10] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000];
+
+#[derive(Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; DRAW_TIME_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let micros = value.as_micros() as u64;
+        for (bound, bucket) in DRAW_TIME_BUCKETS.iter().zip(&self.buckets) {
+            if micros <= *bound {
+                SessionMetrics::add(bucket, 1);
+            }
+        }
+        SessionMetrics::add(&self.count, 1);
+        SessionMetrics::add(&self.sum_nanos, value.as_nanos() as u64);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: DRAW_TIME_BUCKETS
+                .iter()
+                .zip(&self.buckets)
+                .map(|(bound, bucket)| {
+                    (Duration::from_micros(*bound), bucket.load(Ordering::Relaxed))
+                })
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Keeps a session listed in the registry until dropped
+pub(crate) struct SessionGuard {
+    pub id: u64,
+    pub connection: u64,
+    pub addr: Option<SocketAddr>,
+    pub started: Instant,
+    pub metrics: Arc<SessionMetrics>,
+    /// Every log line of the session is recorded inside this span
+    pub span: Span,
+    registry: SessionRegistry,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.registry
+            .inner
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.id);
+    }
+}